@@ -9,14 +9,33 @@ use std::{
     io::{Result, BufReader, BufRead, Error, ErrorKind},
 };
 
+// - modules
+#[cfg(feature = "std")]
+mod lime;
+
+// - re-exports
+#[cfg(feature = "std")]
+pub use lime::*;
+
 // This file exists to enable the library target.
 pub const BUFFER_SIZE: usize = 16384;
 pub const QUEUE_SIZE: u32 = 64;
 pub const MAX_QUEUE_SIZE: usize = QUEUE_SIZE as usize * BUFFER_SIZE;
+/// size of the ring buffer (BPF_MAP_TYPE_RINGBUF, since Linux 5.8), which can hold all records of a read request.
+pub const RINGBUF_SIZE: u32 = 4 * 1024 * 1024;
+pub const RINGBUF_RECORD_HEADER_SIZE: usize = 16;
+/// unreadable memory is reported (and zero filled) with this granularity.
+pub const READ_PAGE_SIZE: usize = 4096;
+pub const PAGES_PER_BUFFER: usize = BUFFER_SIZE / READ_PAGE_SIZE;
+/// readable zones are buffered in gap mode split (until their end is known), larger zones are written as several records.
+pub const MAX_SPLIT_RECORD_SIZE: usize = 64 * MAX_QUEUE_SIZE;
+const READ_STATUS_PAGE_WORDS: usize = MAX_QUEUE_SIZE / READ_PAGE_SIZE / 64;
 
 // IOMEM
 pub const PROC_IOMEM: &str = "/proc/iomem";
 pub const SEPARATOR_SYSTEM_RAM: &str = " : System RAM";
+pub const SEPARATOR_IOMEM_TYPE: &str = " : ";
+pub const IOMEM_TYPE_SYSTEM_RAM: &str = "System RAM";
 pub const SEPARATOR_HYPHEN: char = '-';
 
 // KALLSYMS
@@ -25,7 +44,9 @@ pub const PAGE_OFFSET_BASE: &str = "page_offset_base";
 #[cfg(feature = "std")]
 const PROC_KPTR_RESTRICT: &str = "/proc/sys/kernel/kptr_restrict";
 #[cfg(feature = "std")]
-const PROC_OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+pub const PROC_OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+#[cfg(feature = "std")]
+pub const PROC_HOSTNAME: &str = "/proc/sys/kernel/hostname";
 #[cfg(feature = "std")]
 const SYSTEMMAP_PREFIX: &str = "/boot/System.map-";
 
@@ -34,6 +55,42 @@ pub const PROC_SELF_EXE: &str = "/proc/self/exe";
 
 // eBPF fns
 pub const READ_KERNEL_MEM: &str = "read_kernel_memory";
+pub const READ_KERNEL_MEM_SLOT: &str = "read_kernel_memory_slot";
+
+// eBPF maps
+pub const MAP_BUFFER_QUEUE: &str = "BUFFER_QUEUE";
+pub const MAP_BUFFER_RING: &str = "BUFFER_RING";
+pub const MAP_BUFFER_SLOTS: &str = "BUFFER_SLOTS";
+pub const MAP_BUFFER_SLOTS_STATUS: &str = "BUFFER_SLOTS_STATUS";
+pub const MAP_READ_STATUS: &str = "READ_STATUS";
+
+// error codes of the eBPF programs (see ReadStatus)
+pub const READ_ERROR_SOURCE_ADDRESS: u32 = 1;
+pub const READ_ERROR_DUMP_SIZE: u32 = 2;
+pub const READ_ERROR_DUMP_SIZE_TOO_LARGE: u32 = 3;
+/// some pages could not be read (they are zeroed and marked in the failed pages).
+pub const READ_ERROR_PAGES: u32 = 4;
+/// the transport is full (or the ring buffer record could not be reserved).
+pub const READ_ERROR_PUSH: u32 = 5;
+pub const READ_ERROR_SLOT: u32 = 6;
+pub const READ_ERROR_SLOT_OUT_OF_RANGE: u32 = 7;
+pub const READ_ERROR_BUFFER: u32 = 8;
+
+// LiME / AVML
+pub const LIME_MAGIC: u32 = 0x4C694D45;
+pub const LIME_HEADER_VERSION: u32 = 1;
+pub const AVML_MAGIC: u32 = 0x4C4D5641;
+pub const AVML_HEADER_VERSION: u32 = 2;
+pub const LIME_HEADER_SIZE: u64 = 32;
+/// maximum size of a single (uncompressed) AVML block.
+pub const AVML_MAX_BLOCK_SIZE: u64 = 0x1000 * 0x1000;
+/// size of the blocks, which are checked for zeros (and skipped) in sparse output files.
+pub const SPARSE_BLOCK_SIZE: u64 = 0x1000;
+
+// network
+/// marks the trailer at the end of a plain TCP transfer (followed by the length and the SHA-256 digest of the data).
+pub const NETWORK_TRAILER_MAGIC: &[u8; 8] = b"EMDTRAIL";
+pub const NETWORK_TRAILER_SIZE: usize = 8 + 8 + 32;
 
 // - Errors
 pub const ERROR_DUMP_MEMORY_IOMEM_SEPARATE_KEY_VAL_MAP: &str = "There is no left side in key/value pair";
@@ -52,8 +109,8 @@ pub struct LimeHeader {
 impl Default for LimeHeader {
     fn default() -> Self {
         Self {
-            magic_bytes: 0x4C694D45,
-            header_version: 1,
+            magic_bytes: LIME_MAGIC,
+            header_version: LIME_HEADER_VERSION,
             start_address: 0,
             end_address: 0,
             reserved_space: [0u8; 8],
@@ -70,6 +127,30 @@ impl LimeHeader {
         }
     }
 
+    /// Creates a version 2 header, as used by AVML for snappy compressed ranges.
+    pub fn new_avml(start_address: u64, end_address: u64) -> Self {
+        Self {
+            magic_bytes: AVML_MAGIC,
+            header_version: AVML_HEADER_VERSION,
+            start_address,
+            end_address,
+            ..Default::default()
+        }
+    }
+
+    /// Parses a header (the magic bytes and the version are not validated).
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut reserved_space = [0u8; 8];
+        reserved_space.copy_from_slice(&bytes[24..32]);
+        Self {
+            magic_bytes: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            header_version: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            start_address: u64::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15]]),
+            end_address: u64::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19], bytes[20], bytes[21], bytes[22], bytes[23]]),
+            reserved_space,
+        }
+    }
+
     pub fn as_bytes(&self) -> [u8; 32] {
         let mut bytes = [0u8; 32];
         bytes[0..4].copy_from_slice(&self.magic_bytes.to_le_bytes());
@@ -81,13 +162,227 @@ impl LimeHeader {
     }
 }
 
+// ELF
+pub const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+pub const ELF_CLASS_64: u8 = 2;
+pub const ELF_DATA_LSB: u8 = 1;
+pub const ELF_VERSION_CURRENT: u8 = 1;
+pub const ELF_TYPE_CORE: u16 = 4;
+#[cfg(target_arch = "aarch64")]
+pub const ELF_MACHINE: u16 = 183; // EM_AARCH64
+#[cfg(not(target_arch = "aarch64"))]
+pub const ELF_MACHINE: u16 = 62; // EM_X86_64
+pub const ELF_HEADER_SIZE: usize = 64;
+pub const ELF_PROGRAM_HEADER_SIZE: usize = 56;
+pub const ELF_PAGE_SIZE: u64 = 4096;
+pub const PT_LOAD: u32 = 1;
+pub const PT_NOTE: u32 = 4;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// ELF notes (owner: EMD_NOTE_NAME)
+pub const EMD_NOTE_NAME: &str = "EMD";
+pub const EMD_NOTE_OSRELEASE: u32 = 1;
+pub const EMD_NOTE_PAGE_OFFSET_BASE: u32 = 2;
+pub const EMD_NOTE_VERSION: u32 = 3;
+pub const EMD_NOTE_PID: u32 = 4;
+pub const EMD_NOTE_PROC_MAPS: u32 = 5;
+
+/// The ELF64 file header of a core file.
+#[derive(Debug)]
+pub struct ElfHeader {
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_phnum: u16,
+}
+
+impl Default for ElfHeader {
+    fn default() -> Self {
+        Self {
+            e_type: ELF_TYPE_CORE,
+            e_machine: ELF_MACHINE,
+            e_entry: 0,
+            e_phoff: ELF_HEADER_SIZE as u64,
+            e_shoff: 0,
+            e_flags: 0,
+            e_phnum: 0,
+        }
+    }
+}
+
+impl ElfHeader {
+    pub fn new(e_phnum: u16) -> Self {
+        Self {
+            e_phnum,
+            ..Default::default()
+        }
+    }
+
+    /// Parses a header (the identification bytes, e.g. the magic bytes and the class, are not validated).
+    pub fn from_bytes(bytes: &[u8; ELF_HEADER_SIZE]) -> Self {
+        Self {
+            e_type: u16::from_le_bytes([bytes[16], bytes[17]]),
+            e_machine: u16::from_le_bytes([bytes[18], bytes[19]]),
+            e_entry: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            e_phoff: u64::from_le_bytes(bytes[32..40].try_into().unwrap()),
+            e_shoff: u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
+            e_flags: u32::from_le_bytes(bytes[48..52].try_into().unwrap()),
+            e_phnum: u16::from_le_bytes([bytes[56], bytes[57]]),
+        }
+    }
+
+    pub fn as_bytes(&self) -> [u8; ELF_HEADER_SIZE] {
+        let mut bytes = [0u8; ELF_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&ELF_MAGIC);
+        bytes[4] = ELF_CLASS_64;
+        bytes[5] = ELF_DATA_LSB;
+        bytes[6] = ELF_VERSION_CURRENT;
+        // bytes[7..16]: OS ABI (System V), ABI version and padding are zero.
+        bytes[16..18].copy_from_slice(&self.e_type.to_le_bytes());
+        bytes[18..20].copy_from_slice(&self.e_machine.to_le_bytes());
+        bytes[20..24].copy_from_slice(&(ELF_VERSION_CURRENT as u32).to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.e_entry.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.e_phoff.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.e_shoff.to_le_bytes());
+        bytes[48..52].copy_from_slice(&self.e_flags.to_le_bytes());
+        bytes[52..54].copy_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        bytes[54..56].copy_from_slice(&(ELF_PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        bytes[56..58].copy_from_slice(&self.e_phnum.to_le_bytes());
+        // bytes[58..64]: no section headers.
+        bytes
+    }
+}
+
+/// An ELF64 program header (PT_LOAD or PT_NOTE in emd core files).
+#[derive(Debug, Default)]
+pub struct ElfProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+impl ElfProgramHeader {
+    pub fn from_bytes(bytes: &[u8; ELF_PROGRAM_HEADER_SIZE]) -> Self {
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Self {
+            p_type: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            p_flags: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            p_offset: u64_at(8),
+            p_vaddr: u64_at(16),
+            p_paddr: u64_at(24),
+            p_filesz: u64_at(32),
+            p_memsz: u64_at(40),
+            p_align: u64_at(48),
+        }
+    }
+
+    pub fn as_bytes(&self) -> [u8; ELF_PROGRAM_HEADER_SIZE] {
+        let mut bytes = [0u8; ELF_PROGRAM_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.p_type.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.p_flags.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.p_offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.p_vaddr.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.p_paddr.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.p_filesz.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.p_memsz.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.p_align.to_le_bytes());
+        bytes
+    }
+}
+
+/// Encodes a single ELF note (name and descriptor are padded to 4 bytes).
+#[cfg(feature = "std")]
+pub fn elf_note_as_bytes(name: &str, n_type: u32, desc: &[u8]) -> Vec<u8> {
+    let namesz = name.len() + 1; // including the trailing NUL
+    let mut bytes = Vec::with_capacity(12 + align4(namesz) + align4(desc.len()));
+    bytes.extend_from_slice(&(namesz as u32).to_le_bytes());
+    bytes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&n_type.to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.resize(12 + align4(namesz), 0);
+    bytes.extend_from_slice(desc);
+    bytes.resize(12 + align4(namesz) + align4(desc.len()), 0);
+    bytes
+}
+
+#[cfg(feature = "std")]
+fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+/// A record of the ring buffer transport: the source address and size of the read element, followed by the data.
+#[repr(C)]
+pub struct RingBufRecord {
+    pub address: u64,
+    pub size: u64,
+    pub data: [u8; BUFFER_SIZE],
+}
+
+/// The result of a read request, written by the eBPF program into the status map.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ReadStatus {
+    /// the source address of the request (to detect a status, which is left over from a previous request).
+    pub address: u64,
+    /// 0 or one of the READ_ERROR_* codes.
+    pub code: u32,
+    pub _padding: u32,
+    /// the address of the first page, which could not be read.
+    pub failed_address: u64,
+    /// bytes, which were pushed into the transport (including zeroed pages).
+    pub bytes_pushed: u64,
+    /// bitmask of the pages (READ_PAGE_SIZE), which could not be read.
+    pub failed_pages: [u64; READ_STATUS_PAGE_WORDS],
+}
+
+impl ReadStatus {
+    pub fn new(address: u64) -> Self {
+        Self {
+            address,
+            ..Default::default()
+        }
+    }
+
+    /// Marks the pages of an element as failed: pages is the bitmask of the failed pages (PAGES_PER_BUFFER bits),
+    /// first_page the index of the first page of the element (relative to the start address of the request)
+    /// and address the address of the element.
+    pub fn set_pages_failed(&mut self, first_page: usize, pages: u64, address: u64) {
+        if first_page / 64 < READ_STATUS_PAGE_WORDS {
+            self.failed_pages[first_page / 64] |= pages << (first_page % 64);
+        }
+        if self.code == 0 {
+            self.code = READ_ERROR_PAGES;
+            self.failed_address = address + pages.trailing_zeros() as u64 * READ_PAGE_SIZE as u64;
+        }
+    }
+
+    pub fn page_failed(&self, page: usize) -> bool {
+        page / 64 < READ_STATUS_PAGE_WORDS && self.failed_pages[page / 64] & (1 << (page % 64)) != 0
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReadStatus {}
+
+/// The header, which was written in front of a range.
+#[deprecated(note = "the layout of an image is defined by the ImageFormat trait of emdumper")]
 pub enum Header {
     None,
     Lime(LimeHeader),
 }
 
 pub fn calc_queue_elements(dump_size: usize) -> usize {
-    if dump_size % BUFFER_SIZE == 0 {
+    if dump_size.is_multiple_of(BUFFER_SIZE) {
             dump_size / BUFFER_SIZE
     } else {
         dump_size / BUFFER_SIZE + 1
@@ -127,8 +422,7 @@ pub fn calc_queue_elements(dump_size: usize) -> usize {
 #[cfg(feature = "std")]
 pub fn extract_mem_range<I: Into<String>>(identifier: I) -> Result<Vec<Range<u64>>> {
     let identifier = identifier.into();
-    let path = Path::new(PROC_IOMEM);
-    let file = File::open(path)?;
+    let file = File::open(PROC_IOMEM)?;
     let reader = BufReader::new(file);
     let mut ranges = Vec::new();
 
@@ -144,6 +438,37 @@ pub fn extract_mem_range<I: Into<String>>(identifier: I) -> Result<Vec<Range<u64
     Ok(ranges)
 }
 
+/// Returns the top-level iomem regions of the given type (e.g. "System RAM"). Unlike extract_mem_range, the type has
+/// to match exactly and nested regions (the indented lines) are ignored.
+#[cfg(feature = "std")]
+pub fn extract_iomem_ranges(iomem_type: &str) -> Result<Vec<Range<u64>>> {
+    extract_iomem_ranges_from_file(PROC_IOMEM, iomem_type)
+}
+
+/// Like extract_iomem_ranges, but reads the given copy of /proc/iomem (e.g. a snapshot of the acquired system).
+#[cfg(feature = "std")]
+pub fn extract_iomem_ranges_from_file<P: AsRef<Path>>(path: P, iomem_type: &str) -> Result<Vec<Range<u64>>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut ranges = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        if let Some((_, region_type)) = line.split_once(SEPARATOR_IOMEM_TYPE) {
+            if region_type.trim_end() == iomem_type {
+                if let Some((start, end)) = parse_memory_range(&line) {
+                    ranges.push(start..(end + 1));
+                }
+            }
+        }
+    }
+
+    Ok(ranges)
+}
+
 #[cfg(feature = "std")]
 pub fn get_page_offset_base_address_from_file() -> Result<u64>{
     let file = match get_kptr_restrict()? {
@@ -165,9 +490,21 @@ pub fn get_page_offset_base_address_from_file() -> Result<u64>{
     Ok(0)
 }
 
+/// Returns the release of the running kernel (e.g. "6.8.0-45-generic").
+#[cfg(feature = "std")]
+pub fn get_os_release() -> Result<String> {
+    Ok(read_to_string(PROC_OSRELEASE)?.trim().to_string())
+}
+
+/// Returns the hostname of the system.
+#[cfg(feature = "std")]
+pub fn get_hostname() -> Result<String> {
+    Ok(read_to_string(PROC_HOSTNAME)?.trim().to_string())
+}
+
 #[cfg(feature = "std")]
 fn get_system_map_fd() -> Result<File> {
-    let os_release = read_to_string(PROC_OSRELEASE)?;
+    let os_release = get_os_release()?;
     let path = Path::new(SYSTEMMAP_PREFIX).join(os_release);
     File::open(path)
 }
@@ -218,4 +555,32 @@ impl TryFrom<u8> for KptrRestrict {
             _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{value}"))),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iomem_ranges_match_top_level_types_exactly() {
+        let path = std::env::temp_dir().join(format!("emd-iomem-{}", std::process::id()));
+        std::fs::write(&path, "\
+00001000-0009efff : System RAM
+0009f000-000fffff : Reserved
+  000a0000-000bffff : PCI Bus 0000:00
+  000f0000-000fffff : System ROM
+00100000-5894bfff : System RAM
+  01000000-01ffffff : Kernel code
+6ed6f000-6fbcefff : ACPI Non-volatile Storage
+6fbcf000-6fc4efff : ACPI Tables
+7d800000-dfffffff : PCI Bus 0000:00
+").unwrap();
+        let ranges = |iomem_type| extract_iomem_ranges_from_file(&path, iomem_type).unwrap();
+        assert_eq!(ranges("System RAM"), vec![0x1000..0x9f000, 0x100000..0x5894c000]);
+        assert_eq!(ranges("ACPI Tables"), vec![0x6fbcf000..0x6fc4f000]);
+        assert_eq!(ranges("PCI Bus 0000:00"), vec![0x7d800000..0xe0000000]);
+        assert!(ranges("ACPI").is_empty());
+        assert!(ranges("PCI").is_empty());
+        assert!(ranges("Kernel code").is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+// - STD
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom},
+};
+
+// - parent
+use super::*;
+
+// - External
+use snap::read::FrameDecoder;
+
+// chunk types of the snappy framing format (version 2 records).
+const SNAPPY_CHUNK_COMPRESSED: u8 = 0x00;
+const SNAPPY_CHUNK_UNCOMPRESSED: u8 = 0x01;
+const SNAPPY_CHUNK_STREAM_IDENTIFIER: u8 = 0xff;
+const SNAPPY_CHUNK_HEADER_SIZE: u64 = 4;
+const SNAPPY_CHECKSUM_SIZE: u64 = 4;
+
+/// A record of a LiME image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimeRecord {
+    /// 1 (LiME) or 2 (snappy compressed, as written by AVML).
+    pub version: u32,
+    /// the physical range of the record (end exclusive).
+    pub range: Range<u64>,
+    /// the offset of the header in the image.
+    pub header_offset: u64,
+    /// the offset of the data in the image.
+    pub data_offset: u64,
+    /// the size of the data in the image (the compressed size of version 2 records).
+    pub data_size: u64,
+}
+
+/// A problem of a LiME image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimeIssue {
+    /// the header at the given offset is invalid (the image is not parsed any further).
+    Malformed { offset: u64, reason: String },
+    /// the image ends within the header or the data of the record at the given offset.
+    Truncated { offset: u64, range: Option<Range<u64>>, missing: u64 },
+    /// the physical ranges of two records (indices of records()) overlap.
+    Overlap { record: usize, other: usize },
+    /// the record starts below the previous record (LiME records are ordered by address).
+    Unordered { record: usize },
+}
+
+impl fmt::Display for LimeIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimeIssue::Malformed { offset, reason } => write!(f, "malformed record at offset {offset}: {reason}"),
+            LimeIssue::Truncated { offset, range: Some(range), missing } =>
+                write!(f, "truncated record 0x{:x} - 0x{:x} at offset {offset} ({missing} bytes missing)", range.start, range.end),
+            LimeIssue::Truncated { offset, range: None, missing } => write!(f, "truncated header at offset {offset} ({missing} bytes missing)"),
+            LimeIssue::Overlap { record, other } => write!(f, "record {record} overlaps record {other}"),
+            LimeIssue::Unordered { record } => write!(f, "record {record} starts below the previous record"),
+        }
+    }
+}
+
+/// Reads a LiME image (version 1 and snappy compressed version 2 records).
+///
+/// All headers are parsed (and validated) by new(), the problems are reported by issues(). The reader itself is a
+/// view of the physical memory: the position is a physical address, zones which are not covered by a record
+/// are read as zeros and the end is the end of the highest record.
+pub struct LimeReader<R> {
+    inner: R,
+    records: Vec<LimeRecord>,
+    issues: Vec<LimeIssue>,
+    /// the indices of the records, ordered by their start address.
+    index: Vec<usize>,
+    position: u64,
+    /// the decompressed data of the last read version 2 record.
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> LimeReader<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let size = inner.seek(SeekFrom::End(0))?;
+        let mut records = Vec::new();
+        let mut issues = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            match read_record(&mut inner, offset, size)? {
+                Ok(record) => {
+                    let trailer_size = if record.version == AVML_HEADER_VERSION { 8 } else { 0 };
+                    let Some(end) = record.data_offset.checked_add(record.data_size).and_then(|end| end.checked_add(trailer_size)) else {
+                        issues.push(LimeIssue::Malformed { offset, reason: "the record exceeds the maximum image size".to_string() });
+                        break;
+                    };
+                    offset = end;
+                    records.push(record);
+                },
+                Err(issue) => {
+                    issues.push(issue);
+                    break;
+                },
+            }
+        }
+
+        for (record, pair) in records.windows(2).enumerate() {
+            if pair[1].range.start < pair[0].range.start {
+                issues.push(LimeIssue::Unordered { record: record + 1 });
+            }
+        }
+        let mut index: Vec<usize> = (0..records.len()).collect();
+        index.sort_by_key(|&record| records[record].range.start);
+        let mut highest: Option<usize> = None;
+        for &record in &index {
+            if let Some(other) = highest {
+                if records[record].range.start < records[other].range.end {
+                    issues.push(LimeIssue::Overlap { record: record.max(other), other: record.min(other) });
+                }
+                if records[record].range.end <= records[other].range.end {
+                    continue;
+                }
+            }
+            highest = Some(record);
+        }
+
+        Ok(Self { inner, records, issues, index, position: 0, cache: None })
+    }
+
+    /// The valid records of the image, in the order of the image.
+    pub fn records(&self) -> &[LimeRecord] {
+        &self.records
+    }
+
+    pub fn issues(&self) -> &[LimeIssue] {
+        &self.issues
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The end of the highest record (the size of the physical view).
+    pub fn end(&self) -> u64 {
+        self.records.iter().map(|record| record.range.end).max().unwrap_or(0)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the record, which contains the given address, and the start of the next record.
+    fn record_at(&self, address: u64) -> (Option<usize>, Option<u64>) {
+        let next = self.index.partition_point(|&record| self.records[record].range.start <= address);
+        let next_start = self.index.get(next).map(|&record| self.records[record].range.start);
+        let record = next.checked_sub(1)
+            .map(|previous| self.index[previous])
+            .filter(|&record| self.records[record].range.contains(&address));
+        (record, next_start)
+    }
+
+    fn read_record_data(&mut self, record: usize, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let LimeRecord { version, range, data_offset, data_size, .. } = self.records[record].clone();
+        if version == LIME_HEADER_VERSION {
+            self.inner.seek(SeekFrom::Start(data_offset + offset))?;
+            return self.inner.read(buf);
+        }
+        if self.cache.as_ref().is_none_or(|(cached, _)| *cached != record) {
+            self.inner.seek(SeekFrom::Start(data_offset))?;
+            // the size of the record is at most AVML_MAX_BLOCK_SIZE (see lime_record_range), but the frames are only
+            // checked by their headers, so the decompressed data is bounded.
+            let mut data = Vec::with_capacity((range.end - range.start) as usize);
+            FrameDecoder::new((&mut self.inner).take(data_size))
+                .take((range.end - range.start).saturating_add(1))
+                .read_to_end(&mut data)?;
+            if data.len() as u64 != range.end - range.start {
+                return Err(Error::new(ErrorKind::InvalidData, format!("the record 0x{:x} - 0x{:x} contains {} bytes", range.start, range.end, data.len())));
+            }
+            self.cache = Some((record, data));
+        }
+        let data = &self.cache.as_ref().unwrap().1[offset as usize..];
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Read for LimeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let end = self.end();
+        if buf.is_empty() || self.position >= end {
+            return Ok(0);
+        }
+        let size = match self.record_at(self.position) {
+            (Some(record), _) => {
+                let range = self.records[record].range.clone();
+                let size = buf.len().min((range.end - self.position) as usize);
+                self.read_record_data(record, self.position - range.start, &mut buf[..size])?
+            },
+            (None, next_start) => {
+                // zones without record are read as zeros.
+                let size = buf.len().min((next_start.unwrap_or(end) - self.position) as usize);
+                buf[..size].fill(0);
+                size
+            },
+        };
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for LimeReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.end().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"));
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Validates the given header (version 1 or 2) and returns the physical range of the record (end exclusive). Used by
+/// LimeReader and by the sequential parsers of images, which cannot be seeked (e.g. compressed images).
+pub fn lime_record_range(header: &LimeHeader) -> core::result::Result<Range<u64>, String> {
+    match (header.magic_bytes, header.header_version) {
+        (LIME_MAGIC, LIME_HEADER_VERSION) | (AVML_MAGIC, AVML_HEADER_VERSION) => (),
+        (LIME_MAGIC | AVML_MAGIC, version) => return Err(format!("unsupported version {version}")),
+        (magic, _) => return Err(format!("invalid magic 0x{magic:08x}")),
+    }
+    // the end address of LiME headers is inclusive.
+    if header.end_address < header.start_address || header.end_address == u64::MAX {
+        return Err(format!("invalid range 0x{:x} - 0x{:x}", header.start_address, header.end_address));
+    }
+    let range = header.start_address..header.end_address + 1;
+    // the data of a version 2 record is decompressed as a whole, so its size is limited like by AVML.
+    if header.header_version == AVML_HEADER_VERSION && range.end - range.start > AVML_MAX_BLOCK_SIZE {
+        return Err(format!("the version 2 record 0x{:x} - 0x{:x} exceeds the maximum block size", range.start, range.end - 1));
+    }
+    Ok(range)
+}
+
+/// Reads the record at the given offset. Returns the issue instead, if the record is malformed or truncated.
+fn read_record<R: Read + Seek>(inner: &mut R, offset: u64, size: u64) -> Result<core::result::Result<LimeRecord, LimeIssue>> {
+    if size - offset < LIME_HEADER_SIZE {
+        return Ok(Err(LimeIssue::Truncated { offset, range: None, missing: LIME_HEADER_SIZE - (size - offset) }));
+    }
+    let mut bytes = [0u8; LIME_HEADER_SIZE as usize];
+    inner.seek(SeekFrom::Start(offset))?;
+    inner.read_exact(&mut bytes)?;
+    let header = LimeHeader::from_bytes(&bytes);
+    let malformed = |reason: String| Ok(Err(LimeIssue::Malformed { offset, reason }));
+    let range = match lime_record_range(&header) {
+        Ok(range) => range,
+        Err(reason) => return malformed(reason),
+    };
+    let data_offset = offset + LIME_HEADER_SIZE;
+    let data_size = if header.header_version == LIME_HEADER_VERSION {
+        range.end - range.start
+    } else {
+        match compressed_size(inner, data_offset, size, range.end - range.start)? {
+            Ok(data_size) => data_size,
+            Err(CompressedSizeError::Truncated(missing)) => return Ok(Err(LimeIssue::Truncated { offset, range: Some(range), missing })),
+            Err(CompressedSizeError::Malformed(reason)) => return malformed(reason),
+        }
+    };
+    // the size of version 2 records is checked by compressed_size.
+    let Some(data_end) = data_offset.checked_add(data_size) else {
+        return malformed(format!("the record 0x{:x} - 0x{:x} exceeds the maximum image size", range.start, range.end - 1));
+    };
+    if data_end > size {
+        return Ok(Err(LimeIssue::Truncated { offset, range: Some(range), missing: data_end - size }));
+    }
+    Ok(Ok(LimeRecord { version: header.header_version, range, header_offset: offset, data_offset, data_size }))
+}
+
+enum CompressedSizeError {
+    Truncated(u64),
+    Malformed(String),
+}
+
+/// Returns the size of the snappy frames of a version 2 record with the given (uncompressed) size, by walking
+/// the chunk headers. The frames are followed by their size (u64), which has to match.
+fn compressed_size<R: Read + Seek>(inner: &mut R, data_offset: u64, size: u64, data_size: u64) -> Result<core::result::Result<u64, CompressedSizeError>> {
+    let mut position = data_offset;
+    let mut decompressed = 0;
+    while decompressed < data_size {
+        if position + SNAPPY_CHUNK_HEADER_SIZE > size {
+            return Ok(Err(CompressedSizeError::Truncated(position + SNAPPY_CHUNK_HEADER_SIZE - size)));
+        }
+        let mut chunk_header = [0u8; SNAPPY_CHUNK_HEADER_SIZE as usize];
+        inner.seek(SeekFrom::Start(position))?;
+        inner.read_exact(&mut chunk_header)?;
+        let chunk_size = u32::from_le_bytes([chunk_header[1], chunk_header[2], chunk_header[3], 0]) as u64;
+        let chunk_end = position + SNAPPY_CHUNK_HEADER_SIZE + chunk_size;
+        if chunk_end > size {
+            return Ok(Err(CompressedSizeError::Truncated(chunk_end - size)));
+        }
+        let chunk_data_size = match chunk_header[0] {
+            SNAPPY_CHUNK_COMPRESSED if chunk_size > SNAPPY_CHECKSUM_SIZE => {
+                // the compressed data starts with its uncompressed size (varint).
+                let mut varint = [0u8; 10];
+                let length = varint.len().min((chunk_size - SNAPPY_CHECKSUM_SIZE) as usize);
+                inner.read_exact(&mut [0u8; SNAPPY_CHECKSUM_SIZE as usize])?;
+                inner.read_exact(&mut varint[..length])?;
+                match decode_varint(&varint[..length]) {
+                    Some(value) => value,
+                    None => return Ok(Err(CompressedSizeError::Malformed(format!("invalid snappy chunk at offset {position}")))),
+                }
+            },
+            SNAPPY_CHUNK_UNCOMPRESSED if chunk_size >= SNAPPY_CHECKSUM_SIZE => chunk_size - SNAPPY_CHECKSUM_SIZE,
+            // padding and skippable chunks.
+            SNAPPY_CHUNK_STREAM_IDENTIFIER | 0x80..=0xfe => 0,
+            chunk_type => return Ok(Err(CompressedSizeError::Malformed(format!("invalid snappy chunk type 0x{chunk_type:02x} at offset {position}")))),
+        };
+        decompressed = match decompressed.checked_add(chunk_data_size) {
+            Some(decompressed) => decompressed,
+            None => return Ok(Err(CompressedSizeError::Malformed(format!("the snappy frames exceed the maximum size at offset {position}")))),
+        };
+        position = chunk_end;
+    }
+    if decompressed != data_size {
+        return Ok(Err(CompressedSizeError::Malformed(format!("the snappy frames contain {decompressed} instead of {data_size} bytes"))));
+    }
+    if position + 8 > size {
+        return Ok(Err(CompressedSizeError::Truncated(position + 8 - size)));
+    }
+    let mut trailer = [0u8; 8];
+    inner.seek(SeekFrom::Start(position))?;
+    inner.read_exact(&mut trailer)?;
+    let compressed_size = position - data_offset;
+    if u64::from_le_bytes(trailer) != compressed_size {
+        return Ok(Err(CompressedSizeError::Malformed(format!("the size of the snappy frames is {compressed_size}, but {} is recorded", u64::from_le_bytes(trailer)))));
+    }
+    Ok(Ok(compressed_size))
+}
+
+fn decode_varint(bytes: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (index, byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as u64) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snap::write::FrameEncoder;
+    use std::io::{Cursor, Write};
+
+    fn v1_record(start: u64, data: &[u8]) -> Vec<u8> {
+        let mut record = LimeHeader::new(start, start + data.len() as u64 - 1).as_bytes().to_vec();
+        record.extend_from_slice(data);
+        record
+    }
+
+    fn v2_record(start: u64, data: &[u8]) -> Vec<u8> {
+        let mut encoder = FrameEncoder::new(Vec::new());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.into_inner().unwrap();
+        let mut record = LimeHeader::new_avml(start, start + data.len() as u64 - 1).as_bytes().to_vec();
+        record.extend_from_slice(&compressed);
+        record.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+        record
+    }
+
+    fn reader(image: Vec<u8>) -> LimeReader<Cursor<Vec<u8>>> {
+        LimeReader::new(Cursor::new(image)).unwrap()
+    }
+
+    #[test]
+    fn valid_v1_and_v2_records() {
+        let v2_data: Vec<u8> = (0..0x2000).map(|i| (i % 13) as u8).collect();
+        let mut image = v1_record(0x1000, &[0xaa; 0x1000]);
+        let v2_offset = image.len() as u64;
+        image.extend(v2_record(0x3000, &v2_data));
+        let mut reader = reader(image);
+        assert!(reader.is_valid(), "{:?}", reader.issues());
+        assert_eq!(reader.records().len(), 2);
+        assert_eq!(reader.records()[0], LimeRecord { version: 1, range: 0x1000..0x2000, header_offset: 0, data_offset: 32, data_size: 0x1000 });
+        assert_eq!(reader.records()[1].version, 2);
+        assert_eq!(reader.records()[1].range, 0x3000..0x5000);
+        assert_eq!(reader.records()[1].header_offset, v2_offset);
+        assert_eq!(reader.end(), 0x5000);
+
+        let mut memory = Vec::new();
+        reader.read_to_end(&mut memory).unwrap();
+        assert_eq!(memory.len(), 0x5000);
+        assert!(memory[..0x1000].iter().all(|byte| *byte == 0));
+        assert!(memory[0x1000..0x2000].iter().all(|byte| *byte == 0xaa));
+        assert!(memory[0x2000..0x3000].iter().all(|byte| *byte == 0));
+        assert_eq!(memory[0x3000..], v2_data);
+    }
+
+    #[test]
+    fn truncated_data() {
+        let mut image = v1_record(0x1000, &[0xaa; 0x1000]);
+        image.extend(v1_record(0x3000, &[0xbb; 0x1000]));
+        image.truncate(image.len() - 0x800);
+        let truncated = reader(image);
+        assert_eq!(truncated.records().len(), 1);
+        assert_eq!(truncated.issues(), [LimeIssue::Truncated { offset: 0x1020, range: Some(0x3000..0x4000), missing: 0x800 }]);
+
+        let mut image = v1_record(0x1000, &[0xaa; 0x1000]);
+        image.truncate(0x10);
+        assert_eq!(reader(image).issues(), [LimeIssue::Truncated { offset: 0, range: None, missing: 0x10 }]);
+    }
+
+    #[test]
+    fn truncated_v2_record() {
+        let data: Vec<u8> = (0..0x2000).map(|i| (i % 13) as u8).collect();
+        let mut image = v2_record(0x1000, &data);
+        image.truncate(image.len() - 4);
+        let reader = reader(image);
+        assert!(reader.records().is_empty());
+        assert!(matches!(reader.issues(), [LimeIssue::Truncated { offset: 0, range: Some(_), .. }]));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut image = v1_record(0x1000, &[0xaa; 0x1000]);
+        let mut second = v1_record(0x3000, &[0xbb; 0x1000]);
+        second[0] ^= 0xff;
+        image.extend(second);
+        let reader = reader(image);
+        assert_eq!(reader.records().len(), 1);
+        assert!(matches!(reader.issues(), [LimeIssue::Malformed { offset: 0x1020, .. }]));
+    }
+
+    #[test]
+    fn overlapping_and_unordered_records() {
+        let mut image = v1_record(0x1000, &[0xaa; 0x2000]);
+        image.extend(v1_record(0x2000, &[0xbb; 0x1000]));
+        assert_eq!(reader(image).issues(), [LimeIssue::Overlap { record: 1, other: 0 }]);
+
+        let mut image = v1_record(0x3000, &[0xaa; 0x1000]);
+        image.extend(v1_record(0x1000, &[0xbb; 0x1000]));
+        let reader = reader(image);
+        assert_eq!(reader.issues(), [LimeIssue::Unordered { record: 1 }]);
+        // the view is ordered by address anyway.
+        assert_eq!(reader.end(), 0x4000);
+    }
+
+    #[test]
+    fn record_size_overflow() {
+        // the data of the record would end behind u64::MAX.
+        let mut image = LimeHeader::new(0, u64::MAX - 1).as_bytes().to_vec();
+        image.extend_from_slice(&[0xaa; 0x100]);
+        let reader = reader(image);
+        assert!(reader.records().is_empty());
+        assert!(matches!(reader.issues(), [LimeIssue::Malformed { offset: 0, .. }]));
+    }
+
+    /// Returns a version 2 record of the given size, with a single compressed chunk, which claims to contain it.
+    fn claimed_v2_record(claimed_size: u64) -> Vec<u8> {
+        let mut frames = vec![SNAPPY_CHUNK_COMPRESSED, 9, 0, 0, 0, 0, 0, 0];
+        let mut varint = claimed_size;
+        while varint >= 0x80 {
+            frames.push(varint as u8 | 0x80);
+            varint >>= 7;
+        }
+        frames.push(varint as u8);
+        frames[1] = (frames.len() - 4) as u8;
+        let mut image = LimeHeader::new_avml(0, claimed_size - 1).as_bytes().to_vec();
+        image.extend_from_slice(&frames);
+        image.extend_from_slice(&(frames.len() as u64).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn oversized_v2_record_is_rejected() {
+        // 32 GiB would be allocated to decompress the record.
+        let oversized = reader(claimed_v2_record(1u64 << 35));
+        assert!(oversized.records().is_empty());
+        assert!(matches!(oversized.issues(), [LimeIssue::Malformed { offset: 0, .. }]));
+        let oversized = reader(claimed_v2_record(AVML_MAX_BLOCK_SIZE + 1));
+        assert!(matches!(oversized.issues(), [LimeIssue::Malformed { offset: 0, .. }]));
+    }
+
+    #[test]
+    fn invalid_v2_data_of_the_maximum_size_is_bounded() {
+        let mut reader = reader(claimed_v2_record(AVML_MAX_BLOCK_SIZE));
+        assert!(reader.is_valid(), "{:?}", reader.issues());
+        assert!(reader.read(&mut [0u8; 0x1000]).is_err());
+    }
+}
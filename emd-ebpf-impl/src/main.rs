@@ -1,18 +1,23 @@
 #![no_std]
 #![no_main]
 
+mod read;
+mod slots;
+
 use aya_ebpf::{
-    helpers::bpf_probe_read_kernel_buf, 
     macros::{map, uprobe}, 
-    maps::{PerCpuArray, Queue}, 
+    maps::{Array, PerCpuArray, Queue}, 
     programs::ProbeContext,
 };
 
 use emd_common::{
-    BUFFER_SIZE, QUEUE_SIZE, MAX_QUEUE_SIZE,
-    calc_queue_elements,
+    BUFFER_SIZE, QUEUE_SIZE, MAX_QUEUE_SIZE, PAGES_PER_BUFFER,
+    READ_ERROR_SOURCE_ADDRESS, READ_ERROR_DUMP_SIZE, READ_ERROR_DUMP_SIZE_TOO_LARGE, READ_ERROR_PUSH, READ_ERROR_BUFFER,
+    ReadStatus, calc_queue_elements,
 };
 
+use read::read_element;
+
 #[cfg(feature = "log")]
 use aya_log_ebpf::{debug, error};
 
@@ -22,6 +27,9 @@ static BUFFER_QUEUE: Queue<[u8; BUFFER_SIZE]> = Queue::<[u8; BUFFER_SIZE]>::with
 #[map] // 
 static BUFFER: PerCpuArray<[u8; BUFFER_SIZE]> = PerCpuArray::<[u8; BUFFER_SIZE]>::with_max_entries(1, 0);
 
+#[map] // 
+static READ_STATUS: Array<ReadStatus> = Array::<ReadStatus>::with_max_entries(1, 0);
+
 #[uprobe]
 fn read_kernel_memory(ctx: ProbeContext) -> u32 {
     #[cfg(feature = "log")]
@@ -32,7 +40,7 @@ fn read_kernel_memory(ctx: ProbeContext) -> u32 {
         None => {
             #[cfg(feature = "log")]
             error!(&ctx, "Cannot read source address from probe-context.");
-            return 1
+            return READ_ERROR_SOURCE_ADDRESS
         },
     };
 
@@ -41,13 +49,21 @@ fn read_kernel_memory(ctx: ProbeContext) -> u32 {
         None => {
             #[cfg(feature = "log")]
             error!(&ctx, "Cannot read dumpsize from probe-context.");
-            return 2
+            return READ_ERROR_DUMP_SIZE
         },
     };
+
+    let status = match READ_STATUS.get_ptr_mut(0) {
+        Some(ptr) => unsafe { &mut *ptr },
+        None => return READ_ERROR_BUFFER,
+    };
+    *status = ReadStatus::new(src_address);
+
     if dump_size > MAX_QUEUE_SIZE {
         #[cfg(feature = "log")]
         error!(&ctx, "Given dump size ({}) is greater than maximum allowed dump size ({})", dump_size, MAX_QUEUE_SIZE);
-        return 3;
+        status.code = READ_ERROR_DUMP_SIZE_TOO_LARGE;
+        return READ_ERROR_DUMP_SIZE_TOO_LARGE;
     }
 
     let queue_elements = calc_queue_elements(dump_size);
@@ -61,34 +77,42 @@ fn read_kernel_memory(ctx: ProbeContext) -> u32 {
             None => {
                 #[cfg(feature = "log")]
                 error!(&ctx, "Cannot assign buffer ptr. This is an application bug.");
-                return 0;
+                status.code = READ_ERROR_BUFFER;
+                return READ_ERROR_BUFFER;
             }
         };
         &mut *ptr
     };
 
-    for i in 0..queue_elements {
-        let queue_element_size = if i == queue_elements -1 && dump_size % BUFFER_SIZE != 0 {
+    // the constant bound lets the verifier prove, that the loop terminates.
+    for i in 0..QUEUE_SIZE as usize {
+        if i >= queue_elements {
+            break;
+        }
+        let queue_element_size = if i == queue_elements -1 && !dump_size.is_multiple_of(BUFFER_SIZE) {
             dump_size % BUFFER_SIZE
         } else {
             BUFFER_SIZE
         };
-        unsafe {
-            let src_ptr = src_address as *const u8;
-            match bpf_probe_read_kernel_buf(src_ptr, &mut buffer[..queue_element_size]) {
-                Err(_) => {
-                    return 4
-                },
-                Ok(_) => {
-                    if BUFFER_QUEUE.push(&(*buffer), 0).is_err() {
-                        return 5;
-                    }
-                    src_address += queue_element_size as u64;
-                }
-            };
+        let result = read_element(src_address, &mut buffer[..queue_element_size]);
+        if BUFFER_QUEUE.push(&(*buffer), 0).is_err() {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Cannot push element to the queue.");
+            status.code = READ_ERROR_PUSH;
+            return READ_ERROR_PUSH;
+        }
+        status.bytes_pushed += queue_element_size as u64;
+        if let Err(failed_pages) = result {
+            if failed_pages != 0 {
+                status.set_pages_failed(i * PAGES_PER_BUFFER, failed_pages, src_address);
+            }
+            // the request ends after an element, which has been read page by page (userspace requests the
+            // remainder again), otherwise the verifier cannot prune the states of the remaining iterations.
+            return status.code;
         }
+        src_address += queue_element_size as u64;
     }
-    0
+    status.code
 }
 
 // future implementation, if https://github.com/aya-rs/aya/issues/221 is implemented
@@ -0,0 +1,35 @@
+use aya_ebpf::{cty::c_void, helpers::{bpf_probe_read_kernel_buf, r#gen::bpf_probe_read_kernel}};
+
+use emd_common::{PAGES_PER_BUFFER, READ_PAGE_SIZE};
+
+/// Reads an element (at most BUFFER_SIZE bytes) into the given buffer. If the element cannot be read at once,
+/// it is read page by page: pages which cannot be read are zeroed.
+/// Returns the bitmask of the pages, which could not be read (see ReadStatus::set_pages_failed), as error,
+/// if the element has been read page by page.
+#[inline(always)]
+pub fn read_element(src_address: u64, buffer: &mut [u8]) -> Result<(), u64> {
+    unsafe {
+        if bpf_probe_read_kernel_buf(src_address as *const u8, buffer).is_ok() {
+            return Ok(());
+        }
+    }
+    // the failed pages are collected without branching on the status, otherwise the verifier has to follow
+    // every combination of readable and unreadable pages.
+    let mut failed_pages = 0;
+    for page in 0..PAGES_PER_BUFFER {
+        let start = page * READ_PAGE_SIZE;
+        if start >= buffer.len() {
+            break;
+        }
+        let end = (start + READ_PAGE_SIZE).min(buffer.len());
+        let page_address = src_address + start as u64;
+        let page_buffer = &mut buffer[start..end];
+        // the helper zeroes the destination, if the page cannot be read, and returns a negative error code:
+        // the sign bit is the failed bit.
+        let ret = unsafe {
+            bpf_probe_read_kernel(page_buffer.as_mut_ptr() as *mut c_void, page_buffer.len() as u32, page_address as *const c_void)
+        };
+        failed_pages |= (ret as u64 >> 63) << page;
+    }
+    Err(failed_pages)
+}
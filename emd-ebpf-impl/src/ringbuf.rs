@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+
+mod read;
+mod slots;
+
+use aya_ebpf::{
+    macros::{map, uprobe},
+    maps::{Array, RingBuf},
+    programs::ProbeContext,
+};
+
+use emd_common::{
+    BUFFER_SIZE, QUEUE_SIZE, MAX_QUEUE_SIZE, RINGBUF_SIZE, PAGES_PER_BUFFER,
+    READ_ERROR_SOURCE_ADDRESS, READ_ERROR_DUMP_SIZE, READ_ERROR_DUMP_SIZE_TOO_LARGE, READ_ERROR_PUSH, READ_ERROR_BUFFER,
+    ReadStatus, RingBufRecord, calc_queue_elements,
+};
+
+use read::read_element;
+
+#[cfg(feature = "log")]
+use aya_log_ebpf::{debug, error};
+
+#[map] // 
+static BUFFER_RING: RingBuf = RingBuf::with_byte_size(RINGBUF_SIZE, 0);
+
+#[map] // 
+static READ_STATUS: Array<ReadStatus> = Array::<ReadStatus>::with_max_entries(1, 0);
+
+/// Reads the kernel memory directly into reserved ring buffer records (without a staging buffer in the eBPF program).
+/// Userspace reads the records without a syscall per element and copies them into its chunk.
+#[uprobe]
+fn read_kernel_memory(ctx: ProbeContext) -> u32 {
+    #[cfg(feature = "log")]
+    debug!(&ctx, "Starting uprobe to read kernel memory (ring buffer).");
+
+    let mut src_address: u64 = match ctx.arg(0) {
+        Some(value) => value,
+        None => {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Cannot read source address from probe-context.");
+            return READ_ERROR_SOURCE_ADDRESS
+        },
+    };
+
+    let dump_size: usize = match ctx.arg(1) {
+        Some(value) => value,
+        None => {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Cannot read dumpsize from probe-context.");
+            return READ_ERROR_DUMP_SIZE
+        },
+    };
+
+    let status = match READ_STATUS.get_ptr_mut(0) {
+        Some(ptr) => unsafe { &mut *ptr },
+        None => return READ_ERROR_BUFFER,
+    };
+    *status = ReadStatus::new(src_address);
+
+    if dump_size > MAX_QUEUE_SIZE {
+        #[cfg(feature = "log")]
+        error!(&ctx, "Given dump size ({}) is greater than maximum allowed dump size ({})", dump_size, MAX_QUEUE_SIZE);
+        status.code = READ_ERROR_DUMP_SIZE_TOO_LARGE;
+        return READ_ERROR_DUMP_SIZE_TOO_LARGE;
+    }
+
+    let queue_elements = calc_queue_elements(dump_size);
+    #[cfg(feature = "log")]
+    debug!(&ctx, "Using {} ring buffer records", queue_elements);
+
+    // the constant bound lets the verifier prove, that the loop terminates.
+    for i in 0..QUEUE_SIZE as usize {
+        if i >= queue_elements {
+            break;
+        }
+        let element_size = if i == queue_elements -1 && !dump_size.is_multiple_of(BUFFER_SIZE) {
+            dump_size % BUFFER_SIZE
+        } else {
+            BUFFER_SIZE
+        };
+        let mut entry = match BUFFER_RING.reserve::<RingBufRecord>(0) {
+            Some(entry) => entry,
+            None => {
+                #[cfg(feature = "log")]
+                error!(&ctx, "Cannot reserve ring buffer record.");
+                status.code = READ_ERROR_PUSH;
+                return READ_ERROR_PUSH;
+            }
+        };
+        let result = unsafe {
+            let record = entry.as_mut_ptr();
+            (*record).address = src_address;
+            (*record).size = element_size as u64;
+            let data = &mut (*record).data;
+            read_element(src_address, &mut data[..element_size])
+        };
+        entry.submit(0);
+        status.bytes_pushed += element_size as u64;
+        if let Err(failed_pages) = result {
+            if failed_pages != 0 {
+                status.set_pages_failed(i * PAGES_PER_BUFFER, failed_pages, src_address);
+            }
+            // the request ends after an element, which has been read page by page (userspace requests the
+            // remainder again), otherwise the verifier cannot prune the states of the remaining iterations.
+            return status.code;
+        }
+        src_address += element_size as u64;
+    }
+    status.code
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
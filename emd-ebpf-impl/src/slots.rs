@@ -0,0 +1,109 @@
+use aya_ebpf::{
+    macros::{map, uprobe},
+    maps::Array,
+    programs::ProbeContext,
+};
+
+use emd_common::{
+    BUFFER_SIZE, QUEUE_SIZE, MAX_QUEUE_SIZE, PAGES_PER_BUFFER,
+    READ_ERROR_SOURCE_ADDRESS, READ_ERROR_DUMP_SIZE, READ_ERROR_DUMP_SIZE_TOO_LARGE, READ_ERROR_SLOT, READ_ERROR_SLOT_OUT_OF_RANGE,
+    ReadStatus, calc_queue_elements,
+};
+
+use crate::read::read_element;
+
+#[cfg(feature = "log")]
+use aya_log_ebpf::{debug, error};
+
+// one slot of QUEUE_SIZE elements per worker thread (see --threads), the size is set by userspace at load time.
+#[map] //
+static BUFFER_SLOTS: Array<[u8; BUFFER_SIZE]> = Array::<[u8; BUFFER_SIZE]>::with_max_entries(QUEUE_SIZE, 0);
+
+// the status of the last read request per slot.
+#[map] //
+static BUFFER_SLOTS_STATUS: Array<ReadStatus> = Array::<ReadStatus>::with_max_entries(1, 0);
+
+/// Reads the kernel memory into the slot of the calling worker thread, so several threads can read in parallel.
+#[uprobe]
+fn read_kernel_memory_slot(ctx: ProbeContext) -> u32 {
+    #[cfg(feature = "log")]
+    debug!(&ctx, "Starting uprobe to read kernel memory (slot).");
+
+    let mut src_address: u64 = match ctx.arg(0) {
+        Some(value) => value,
+        None => {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Cannot read source address from probe-context.");
+            return READ_ERROR_SOURCE_ADDRESS
+        },
+    };
+
+    let dump_size: usize = match ctx.arg(1) {
+        Some(value) => value,
+        None => {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Cannot read dumpsize from probe-context.");
+            return READ_ERROR_DUMP_SIZE
+        },
+    };
+
+    let slot: u32 = match ctx.arg(2) {
+        Some(value) => value,
+        None => {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Cannot read slot from probe-context.");
+            return READ_ERROR_SLOT
+        },
+    };
+
+    let status = match BUFFER_SLOTS_STATUS.get_ptr_mut(slot) {
+        Some(ptr) => unsafe { &mut *ptr },
+        None => {
+            #[cfg(feature = "log")]
+            error!(&ctx, "Slot {} is out of range.", slot);
+            return READ_ERROR_SLOT_OUT_OF_RANGE;
+        }
+    };
+    *status = ReadStatus::new(src_address);
+
+    if dump_size > MAX_QUEUE_SIZE {
+        #[cfg(feature = "log")]
+        error!(&ctx, "Given dump size ({}) is greater than maximum allowed dump size ({})", dump_size, MAX_QUEUE_SIZE);
+        status.code = READ_ERROR_DUMP_SIZE_TOO_LARGE;
+        return READ_ERROR_DUMP_SIZE_TOO_LARGE;
+    }
+
+    let queue_elements = calc_queue_elements(dump_size);
+    // the constant bound lets the verifier prove, that the loop terminates.
+    for i in 0..QUEUE_SIZE as usize {
+        if i >= queue_elements {
+            break;
+        }
+        let element_size = if i == queue_elements -1 && !dump_size.is_multiple_of(BUFFER_SIZE) {
+            dump_size % BUFFER_SIZE
+        } else {
+            BUFFER_SIZE
+        };
+        let buffer = match BUFFER_SLOTS.get_ptr_mut(slot * QUEUE_SIZE + i as u32) {
+            Some(ptr) => unsafe { &mut *ptr },
+            None => {
+                #[cfg(feature = "log")]
+                error!(&ctx, "Slot {} is out of range.", slot);
+                status.code = READ_ERROR_SLOT_OUT_OF_RANGE;
+                return READ_ERROR_SLOT_OUT_OF_RANGE;
+            }
+        };
+        let result = read_element(src_address, &mut buffer[..element_size]);
+        status.bytes_pushed += element_size as u64;
+        if let Err(failed_pages) = result {
+            if failed_pages != 0 {
+                status.set_pages_failed(i * PAGES_PER_BUFFER, failed_pages, src_address);
+            }
+            // the request ends after an element, which has been read page by page (userspace requests the
+            // remainder again), otherwise the verifier cannot prune the states of the remaining iterations.
+            return status.code;
+        }
+        src_address += element_size as u64;
+    }
+    status.code
+}
@@ -4,4 +4,10 @@ use aya::include_bytes_aligned;
 #[cfg(feature = "build")]
 pub const EBPF_BINARY: &[u8] = include_bytes_aligned!(concat!(env!("OUT_DIR"), "/emd_ebpf"));
 #[cfg(not(feature = "build"))]
-pub const EBPF_BINARY: &[u8] = include_bytes_aligned!("../assets/emd_ebpf_prebuild");
\ No newline at end of file
+pub const EBPF_BINARY: &[u8] = include_bytes_aligned!("../assets/emd_ebpf_prebuild");
+
+/// The eBPF binary, which uses a ring buffer (BPF_MAP_TYPE_RINGBUF) as transport (Linux 5.8+).
+#[cfg(feature = "build")]
+pub const EBPF_RINGBUF_BINARY: &[u8] = include_bytes_aligned!(concat!(env!("OUT_DIR"), "/emd_ebpf_ringbuf"));
+#[cfg(not(feature = "build"))]
+pub const EBPF_RINGBUF_BINARY: &[u8] = include_bytes_aligned!("../assets/emd_ebpf_ringbuf_prebuild");
@@ -1,11 +1,16 @@
 // - parent
 use super::*;
 
-pub(crate) fn get_page_offset_base(buffer_queue: &mut Queue<&mut MapData, [u8; BUFFER_SIZE]>) -> anyhow::Result<u64>{
+pub(crate) fn get_page_offset_base(transport: &mut Transport) -> anyhow::Result<u64>{
     let page_offset_base_addr = get_page_offset_base_address_from_file()?;
-    read_kernel_memory(page_offset_base_addr, 8);
-    let slice: &[u8] = &buffer_queue.pop(0)?[..8];
-    Ok(u64::from_le_bytes(slice.try_into()?))
+    let mut page_offset_base = None;
+    transport.read(page_offset_base_addr, 8, |element| {
+        if let (true, Some(data)) = (element.is_readable(), element.data) {
+            page_offset_base = Some(u64::from_le_bytes(data.try_into()?));
+        }
+        Ok(())
+    })?;
+    page_offset_base.ok_or_else(|| anyhow::anyhow!("Could not read page_offset_base at 0x{page_offset_base_addr:x}."))
 }
 
 pub(crate) fn get_base_addr() -> Result<usize, anyhow::Error> {
@@ -22,11 +27,167 @@ pub(crate) fn get_base_addr() -> Result<usize, anyhow::Error> {
     anyhow::bail!("Failed to find executable region")
 }
 
-pub(crate) fn memory_size() -> anyhow::Result<u64> {
-    let memory_ranges = extract_mem_range(SEPARATOR_SYSTEM_RAM)?;
+pub(crate) fn memory_size(memory_ranges: &[Range<u64>]) -> u64 {
     let mut total_size = 0;
     for range in memory_ranges {
         total_size += range.end - range.start;
     }
-    Ok(total_size)
-}
\ No newline at end of file
+    total_size
+}
+
+/// Parses a physical range like in /proc/iomem (the end address is inclusive), e.g. 0x100000-0x5894bfff.
+pub(crate) fn parse_range(value: &str) -> Result<Range<u64>, String> {
+    let (start, end) = value.split_once(SEPARATOR_HYPHEN).ok_or_else(|| format!("invalid range: {value}"))?;
+    let (start, end) = (parse_address(start)?, parse_address(end)?);
+    if start > end {
+        return Err(format!("the start address of {value} is greater than the end address"));
+    }
+    let end = end.checked_add(1).ok_or_else(|| format!("invalid range: {value}"))?;
+    Ok(start..end)
+}
+
+fn parse_address(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let hex = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    u64::from_str_radix(hex, 16).map_err(|e| format!("invalid address {value}: {e}"))
+}
+
+/// Selects the physical ranges to dump: the iomem regions of the chosen types (default: System RAM),
+/// intersected with the chosen ranges (if any) and without the excluded ranges.
+/// All given ranges are validated against the iomem map, before anything is read.
+pub(crate) fn select_memory_ranges(args: &Cli) -> anyhow::Result<Vec<Range<u64>>> {
+    let iomem_types = if args.iomem_types.is_empty() {
+        vec![IOMEM_TYPE_SYSTEM_RAM.to_string()]
+    } else {
+        args.iomem_types.clone()
+    };
+    let mut iomem_ranges = Vec::new();
+    for iomem_type in &iomem_types {
+        let ranges = extract_iomem_ranges(iomem_type)?;
+        if ranges.is_empty() {
+            anyhow::bail!("There is no iomem region of type \"{iomem_type}\" (or it is not readable without CAP_SYS_ADMIN).");
+        }
+        iomem_ranges.extend(ranges);
+    }
+    let mut memory_ranges = merge_ranges(iomem_ranges);
+
+    if !args.ranges.is_empty() {
+        for range in &args.ranges {
+            if !memory_ranges.iter().any(|iomem_range| overlaps(iomem_range, range)) {
+                anyhow::bail!("The range 0x{:x} - 0x{:x} is not part of the selected iomem regions ({}).", range.start, range.end - 1, iomem_types.join(", "));
+            }
+            for zone in difference(std::slice::from_ref(range), &memory_ranges) {
+                warn!("0x{:x} - 0x{:x} is not part of the selected iomem regions ({}) and is not dumped.", zone.start, zone.end - 1, iomem_types.join(", "));
+            }
+        }
+        let selected = merge_ranges(args.ranges.clone());
+        memory_ranges = memory_ranges.iter()
+            .flat_map(|iomem_range| selected.iter().filter_map(|range| intersect(iomem_range, range)))
+            .collect();
+    }
+
+    for excluded in &args.exclude_ranges {
+        memory_ranges = memory_ranges.into_iter().flat_map(|range| subtract(range, excluded)).collect();
+    }
+
+    if memory_ranges.is_empty() {
+        anyhow::bail!("There is no memory left to dump.");
+    }
+    Ok(memory_ranges)
+}
+
+/// Sorts the ranges and merges overlapping ranges (e.g. nested iomem regions). Adjacent ranges are kept (like
+/// adjacent iomem regions, each of them is dumped as a record of its own).
+pub(crate) fn merge_ranges(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fn intersect(a: &Range<u64>, b: &Range<u64>) -> Option<Range<u64>> {
+    overlaps(a, b).then(|| a.start.max(b.start)..a.end.min(b.end))
+}
+
+pub(crate) fn subtract(range: Range<u64>, excluded: &Range<u64>) -> Vec<Range<u64>> {
+    if !overlaps(&range, excluded) {
+        return vec![range];
+    }
+    let mut remaining = Vec::new();
+    if range.start < excluded.start {
+        remaining.push(range.start..excluded.start);
+    }
+    if excluded.end < range.end {
+        remaining.push(excluded.end..range.end);
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_end_is_inclusive() {
+        assert_eq!(parse_range("0x100000-0x5894bfff"), Ok(0x100000..0x5894c000));
+        assert_eq!(parse_range("100000-5894bfff"), Ok(0x100000..0x5894c000));
+        assert_eq!(parse_range("0X1000-0X1FFF"), Ok(0x1000..0x2000));
+        assert_eq!(parse_range(" 0x1000 - 0x1fff "), Ok(0x1000..0x2000));
+        // a single byte.
+        assert_eq!(parse_range("0x1000-0x1000"), Ok(0x1000..0x1001));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!(parse_range("0x1000").is_err());
+        assert!(parse_range("0x2000-0x1000").is_err());
+        assert!(parse_range("0x1000-0xfoo").is_err());
+        assert!(parse_range("-0x1000").is_err());
+        assert!(parse_range("0x1000-0x10000000000000000").is_err());
+        // the exclusive end of u64::MAX is not representable.
+        assert!(parse_range("0x0-0xffffffffffffffff").is_err());
+        assert_eq!(parse_range("0x0-0xfffffffffffffffe"), Ok(0..u64::MAX));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(merge_ranges(vec![0x5000..0x6000, 0x1000..0x3000, 0x2000..0x4000]), [0x1000..0x4000, 0x5000..0x6000]);
+        // nested ranges.
+        assert_eq!(merge_ranges(vec![0x1000..0x8000, 0x2000..0x3000, 0x7000..0x9000]), std::slice::from_ref(&(0x1000..0x9000)));
+        assert_eq!(merge_ranges(vec![0x1000..0x2000, 0x1000..0x2000]), std::slice::from_ref(&(0x1000..0x2000)));
+        assert_eq!(merge_ranges(Vec::new()), []);
+    }
+
+    #[test]
+    fn adjacent_ranges_are_kept() {
+        assert_eq!(merge_ranges(vec![0x2000..0x3000, 0x1000..0x2000]), [0x1000..0x2000, 0x2000..0x3000]);
+    }
+
+    #[test]
+    fn ranges_are_intersected() {
+        assert_eq!(intersect(&(0x1000..0x4000), &(0x2000..0x8000)), Some(0x2000..0x4000));
+        assert_eq!(intersect(&(0x2000..0x3000), &(0x1000..0x8000)), Some(0x2000..0x3000));
+        assert_eq!(intersect(&(0x1000..0x2000), &(0x2000..0x3000)), None);
+        assert_eq!(intersect(&(0x1000..0x2000), &(0x5000..0x6000)), None);
+    }
+
+    #[test]
+    fn ranges_are_subtracted() {
+        // the excluded range splits the range.
+        assert_eq!(subtract(0x1000..0x8000, &(0x3000..0x4000)), [0x1000..0x3000, 0x4000..0x8000]);
+        assert_eq!(subtract(0x1000..0x8000, &(0x0..0x4000)), std::slice::from_ref(&(0x4000..0x8000)));
+        assert_eq!(subtract(0x1000..0x8000, &(0x4000..0x9000)), std::slice::from_ref(&(0x1000..0x4000)));
+        assert_eq!(subtract(0x1000..0x8000, &(0x0..0x9000)), []);
+        assert_eq!(subtract(0x1000..0x2000, &(0x2000..0x3000)), std::slice::from_ref(&(0x1000..0x2000)));
+        assert_eq!(subtract(0x1000..u64::MAX, &(0x2000..u64::MAX)), std::slice::from_ref(&(0x1000..0x2000)));
+    }
+}
@@ -0,0 +1,226 @@
+// - STD
+use std::io::{self, BufRead};
+
+// - parent
+use super::*;
+
+// - External
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use lz4_flex::frame::FrameDecoder as Lz4Decoder;
+use serde::{Deserialize, Serialize};
+use xz2::{read::XzDecoder, write::XzEncoder};
+use zstd::stream::Decoder as ZstdDecoder;
+
+pub(crate) const ZSTD_DEFAULT_LEVEL: i32 = 3;
+pub(crate) const GZIP_DEFAULT_LEVEL: i32 = 6;
+pub(crate) const XZ_DEFAULT_LEVEL: i32 = 6;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// The parameters of the used compression (as written to the acquisition metadata).
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CompressionParameters {
+    pub(crate) codec: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    long_distance_matching: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window_log: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads: Option<u32>,
+}
+
+impl CompressionParameters {
+    pub(crate) fn new(args: &Cli) -> Self {
+        let zstd = matches!(args.compression, Compression::Zstd);
+        Self {
+            codec: value_name(&args.compression),
+            level: compression_level(args),
+            long_distance_matching: zstd.then_some(args.zstd_long),
+            window_log: args.zstd_window_log.filter(|_| zstd),
+            threads: args.compress_threads.filter(|_| zstd),
+        }
+    }
+}
+
+/// Returns the compression level (or the default level of the codec), if the codec supports levels.
+fn compression_level(args: &Cli) -> Option<i32> {
+    let default_level = match args.compression {
+        Compression::Zstd => ZSTD_DEFAULT_LEVEL,
+        Compression::Gzip => GZIP_DEFAULT_LEVEL,
+        Compression::Xz => XZ_DEFAULT_LEVEL,
+        Compression::None | Compression::Lz4 => return None,
+    };
+    Some(args.compress_level.unwrap_or(default_level))
+}
+
+/// Checks, if the given compression options are supported by the selected codec.
+pub(crate) fn check_compression_args(args: &Cli) -> anyhow::Result<()> {
+    let zstd = matches!(args.compression, Compression::Zstd);
+    if args.compress_threads.is_some() && !zstd {
+        anyhow::bail!("--compress-threads is only supported by the zstd compression.");
+    }
+    if (args.zstd_long || args.zstd_window_log.is_some()) && !zstd {
+        anyhow::bail!("--zstd-long and --zstd-window-log are only supported by the zstd compression.");
+    }
+    if let Some(level) = args.compress_level {
+        let levels = match args.compression {
+            Compression::Zstd => zstd::compression_level_range(),
+            Compression::Gzip | Compression::Xz => 0..=9,
+            Compression::None | Compression::Lz4 => anyhow::bail!("--compress-level is not supported by the selected compression."),
+        };
+        if !levels.contains(&level) {
+            anyhow::bail!("The compression level {level} is out of range ({} - {}).", levels.start(), levels.end());
+        }
+    }
+    Ok(())
+}
+
+/// Wraps the given output with the selected compression. The compression is completed by OutputSink::finish.
+pub(crate) fn compression_writer(args: &Cli, output: Box<dyn OutputSink>) -> anyhow::Result<Box<dyn OutputSink>> {
+    let level = compression_level(args);
+    Ok(match args.compression {
+        Compression::None => output,
+        Compression::Zstd => {
+            let mut encoder = ZstdEncoder::new(output, level.unwrap())?;
+            if let Some(threads) = args.compress_threads {
+                encoder.multithread(threads)?;
+            }
+            if args.zstd_long {
+                encoder.long_distance_matching(true)?;
+            }
+            if let Some(window_log) = args.zstd_window_log {
+                encoder.window_log(window_log)?;
+            }
+            Box::new(CompressionSink::Zstd(encoder))
+        },
+        Compression::Lz4 => Box::new(CompressionSink::Lz4(Lz4Encoder::new(output))),
+        Compression::Gzip => Box::new(CompressionSink::Gzip(GzEncoder::new(output, flate2::Compression::new(level.unwrap() as u32)))),
+        Compression::Xz => Box::new(CompressionSink::Xz(XzEncoder::new(output, level.unwrap() as u32))),
+    })
+}
+
+/// The encoder of the selected compression. The end of the compressed stream is written by finish (instead of on
+/// drop), so errors while writing it fail the acquisition.
+enum CompressionSink {
+    Zstd(ZstdEncoder<'static, Box<dyn OutputSink>>),
+    Lz4(Lz4Encoder<Box<dyn OutputSink>>),
+    Gzip(GzEncoder<Box<dyn OutputSink>>),
+    Xz(XzEncoder<Box<dyn OutputSink>>),
+}
+
+impl Write for CompressionSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Lz4(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Lz4(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl OutputSink for CompressionSink {
+    fn finish(&mut self) -> io::Result<()> {
+        let output = match self {
+            Self::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut()
+            },
+            Self::Lz4(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut()
+            },
+            Self::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut()
+            },
+            Self::Xz(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut()
+            },
+        };
+        output.finish()
+    }
+}
+
+/// Detects the compression of an existing image by its magic bytes (the input is not consumed).
+pub(crate) fn detect_compression(input: &mut impl BufRead) -> io::Result<Compression> {
+    let head = input.fill_buf()?;
+    Ok(if head.starts_with(ZSTD_MAGIC) {
+        Compression::Zstd
+    } else if head.starts_with(LZ4_MAGIC) {
+        Compression::Lz4
+    } else if head.starts_with(GZIP_MAGIC) {
+        Compression::Gzip
+    } else if head.starts_with(XZ_MAGIC) {
+        Compression::Xz
+    } else {
+        Compression::None
+    })
+}
+
+/// Wraps the given input with the decompression of the given codec.
+pub(crate) fn decompression_reader<'a>(compression: &Compression, input: impl BufRead + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(input),
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::with_buffer(input)?;
+            // images, which were written with --zstd-window-log, may use windows above the default limit.
+            decoder.window_log_max(31)?;
+            Box::new(decoder)
+        },
+        Compression::Lz4 => Box::new(Lz4Decoder::new(input)),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(input)),
+        Compression::Xz => Box::new(XzDecoder::new(input)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const CODECS: [Compression; 4] = [Compression::Zstd, Compression::Lz4, Compression::Gzip, Compression::Xz];
+
+    fn args(compression: Compression) -> Cli {
+        Cli { compression, ..Cli::default() }
+    }
+
+    #[test]
+    fn finish_completes_the_compressed_stream() {
+        let data: Vec<u8> = (0..0x30000).map(|i| (i % 7) as u8).collect();
+        for compression in CODECS {
+            let sink = SharedSink::default();
+            let mut writer = compression_writer(&args(compression.clone()), Box::new(sink.clone())).unwrap();
+            writer.write_all(&data).unwrap();
+            writer.finish().unwrap();
+            let compressed = sink.data();
+            let mut decompressed = Vec::new();
+            decompression_reader(&compression, compressed.as_slice()).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data, "{}", value_name(&compression));
+        }
+    }
+
+    #[test]
+    fn finish_reports_write_errors() {
+        for compression in CODECS {
+            let mut writer = compression_writer(&args(compression.clone()), Box::new(FailingSink)).unwrap();
+            let result = writer.write_all(b"memory").and_then(|_| writer.finish());
+            assert!(result.is_err(), "{}", value_name(&compression));
+        }
+    }
+}
@@ -0,0 +1,464 @@
+// - STD
+use std::collections::BTreeMap;
+
+// - parent
+use super::*;
+
+// - External
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Converts an existing image (see emd convert) into the chosen output format (and compression or encryption).
+/// The metadata of the image (see --sidecar) is taken over to the metadata of the converted image (see --metadata).
+pub(crate) fn convert(args: &Cli, convert_args: &ConvertArgs) -> anyhow::Result<()> {
+    // the output (and the partial output) is created (truncated), before the image is read.
+    let input_path = convert_args.input.canonicalize().map_err(|e| anyhow::anyhow!("Could not open {}: {e}", convert_args.input.display()))?;
+    for output in [convert_args.output.clone(), partial_path(&convert_args.output)] {
+        if output.canonicalize().is_ok_and(|output_path| output_path == input_path) {
+            anyhow::bail!("The converted image {} would overwrite the image {}.", output.display(), convert_args.input.display());
+        }
+    }
+    let sidecar = match &convert_args.sidecar {
+        Some(path) => Some(read_sidecar(path)?),
+        None => None,
+    };
+    let metadata = sidecar.as_ref().map(ImageMetadata::deserialize).transpose()?;
+
+    let output_algorithms = algorithms(metadata.as_ref().map(|metadata| &metadata.output_hashes), &args.hash);
+    let image_algorithms = algorithms(metadata.as_ref().map(|metadata| &metadata.image_hashes), &args.hash);
+    let mut input = ImageInput::open(&convert_args.input, &output_algorithms, &image_algorithms)?;
+    let compression = input.compression.clone();
+    let mut image = input.image()?;
+    let format = image_format(detect_format(&mut image)?, metadata.as_ref());
+    info!("Converting {} ({}, compression: {}) to {} ({}, compression: {}).",
+        convert_args.input.display(), value_name(&format), value_name(&compression),
+        convert_args.output.display(), value_name(&args.output_format), value_name(&args.compression));
+
+    let interrupted_at = metadata.as_ref().map(interrupted_at).transpose()?.flatten();
+    let (raw_ranges, ranges, page_offset_base) = match &metadata {
+        Some(metadata) => {
+            let raw_ranges = sidecar_ranges(metadata, interrupted_at);
+            let ranges = written_ranges(metadata, &raw_ranges, &args.output_format)?;
+            (raw_ranges, ranges, metadata.page_offset_base)
+        },
+        None if matches!(format, OutputFormat::Raw | OutputFormat::PaddedRaw) =>
+            anyhow::bail!("The ranges of a raw image are unknown, set the metadata of the image with --sidecar."),
+        None => {
+            let (ranges, page_offset_base) = image_ranges(&convert_args.input, &format)?;
+            if page_offset_base.is_none() && matches!(args.output_format, OutputFormat::Elf) {
+                warn!("The page_offset_base is unknown (set the metadata of the image with --sidecar), the virtual addresses of the segments start at 0.");
+            }
+            (Vec::new(), ranges, page_offset_base.unwrap_or_default())
+        },
+    };
+
+    let hashes = ImageHashes::new(args);
+    let partial_output = PartialOutput::new(&convert_args.output);
+    let output = File::create(&partial_output.path).map_err(|e| anyhow::anyhow!("Could not create {}: {e}", partial_output.path.display()))?;
+    let mut output_file = prepare_writer(args, &hashes, file_output(args, output))?;
+    let mut converter = Converter {
+        format: builtin_format(&args.output_format),
+        output: &mut output_file,
+        ranges: &ranges,
+        current: 0,
+        cursor: None,
+        // the range hashes of the metadata are taken over, if there is metadata.
+        range_algorithms: match (&args.metadata, &metadata) {
+            (Some(_), None) => Some(hash_algorithms_or_default(args)),
+            _ => None,
+        },
+        record: None,
+        records: Vec::new(),
+    };
+    converter.format.begin_image(converter.output, &ImageInfo { ranges: &ranges, page_offset_base })?;
+    let summary = read_image(&mut image, &format, &raw_ranges, &mut converter)?;
+    // the last record of an interrupted acquisition (e.g. a LiME record) is truncated.
+    if !(summary.end.is_complete() || interrupted_at.is_some() && converter.is_written()) {
+        anyhow::bail!("The image is incomplete ({}), it is not converted to {}.", summary.end, convert_args.output.display());
+    }
+    converter.finish()?;
+    let records = std::mem::take(&mut converter.records);
+    let mut format_writer = converter.format;
+    format_writer.finish(&mut output_file)?;
+    output_file.finish()?;
+    drop(image);
+    let input_digests = input.finish();
+    let digests = hashes.finalize();
+    if !args.hash.is_empty() {
+        digests.report(args)?;
+    }
+
+    let mut discrepancies = Discrepancies::default();
+    compare_digests("source image", &input_digests.image, metadata.as_ref().map(|metadata| &metadata.image_hashes), &mut discrepancies);
+    compare_digests("source output", &input_digests.output, metadata.as_ref().map(|metadata| &metadata.output_hashes), &mut discrepancies);
+    if discrepancies.0 > 0 {
+        anyhow::bail!("The image {} does not match its metadata ({} discrepancies), it is not converted to {}.",
+            convert_args.input.display(), discrepancies.0, convert_args.output.display());
+    }
+    partial_output.persist()?;
+
+    if let Some(path) = &args.metadata {
+        let source = ConvertedFrom {
+            file: convert_args.input.display().to_string(),
+            output_format: value_name(&format),
+            compression: value_name(&compression),
+            image_hashes: digests_as_map(&input_digests.image),
+            output_hashes: digests_as_map(&input_digests.output),
+            command_line: std::env::args().collect(),
+            time: now(),
+        };
+        let metadata = converted_metadata(args, sidecar, page_offset_base, records, source, &digests)?;
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &metadata)?;
+    }
+    info!("The image {} has been converted to {}.", convert_args.input.display(), convert_args.output.display());
+    Ok(())
+}
+
+/// The converted image, which is written next to the output (<outputfile>.tmp) and only renamed to the output, if
+/// the conversion has succeeded. Otherwise (e.g. if the image is incomplete) it is removed, when dropped.
+struct PartialOutput {
+    path: PathBuf,
+    output: PathBuf,
+    persisted: bool,
+}
+
+fn partial_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".tmp");
+    PathBuf::from(path)
+}
+
+impl PartialOutput {
+    fn new(output: &Path) -> Self {
+        Self { path: partial_path(output), output: output.to_path_buf(), persisted: false }
+    }
+
+    fn persist(mut self) -> anyhow::Result<()> {
+        std::fs::rename(&self.path, &self.output)
+            .map_err(|e| anyhow::anyhow!("Could not rename {} to {}: {e}", self.path.display(), self.output.display()))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The image, which has been converted (as written to the metadata of the converted image).
+#[derive(Serialize)]
+struct ConvertedFrom {
+    file: String,
+    output_format: String,
+    compression: String,
+    image_hashes: BTreeMap<String, String>,
+    output_hashes: BTreeMap<String, String>,
+    command_line: Vec<String>,
+    time: String,
+}
+
+fn read_sidecar(path: &Path) -> anyhow::Result<Value> {
+    let file = File::open(path).map_err(|e| anyhow::anyhow!("Could not open {}: {e}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("Could not parse the metadata {}: {e}", path.display()))
+}
+
+/// Returns the address, where the acquisition of the image has been interrupted (if it has been interrupted).
+fn interrupted_at(metadata: &ImageMetadata) -> anyhow::Result<Option<u64>> {
+    if metadata.status != AcquisitionStatus::Interrupted {
+        return Ok(None);
+    }
+    let interrupted_at = metadata.interrupted_at.as_deref().unwrap_or_default();
+    let address = u64::from_str_radix(interrupted_at.trim_start_matches("0x"), 16)
+        .map_err(|e| anyhow::anyhow!("Invalid interrupted_at {interrupted_at} in the metadata: {e}"))?;
+    warn!("The acquisition has been interrupted at 0x{address:x}, the converted image ends there as well.");
+    Ok(Some(address))
+}
+
+/// Returns the ranges of the metadata (up to the given end).
+fn sidecar_ranges(metadata: &ImageMetadata, end: Option<u64>) -> Vec<Range<u64>> {
+    let end = end.unwrap_or(u64::MAX);
+    metadata.ranges.iter()
+        .filter(|range| range.start < end)
+        .map(|range| range.start..range.end.min(end))
+        .collect()
+}
+
+/// Returns the given ranges of the metadata without the omitted zones (gap mode split).
+fn written_ranges(metadata: &ImageMetadata, ranges: &[Range<u64>], output_format: &OutputFormat) -> anyhow::Result<Vec<Range<u64>>> {
+    let mut written = Vec::new();
+    for (range, range_metadata) in ranges.iter().zip(&metadata.ranges) {
+        let skipped: Vec<Range<u64>> = range_metadata.skipped.iter().map(ZoneMetadata::range).collect();
+        written.extend(difference(std::slice::from_ref(range), &skipped));
+    }
+    if written != ranges && matches!(output_format, OutputFormat::Raw) {
+        anyhow::bail!("The image omits unreadable zones (gap mode split), which cannot be represented by a raw image.");
+    }
+    Ok(written)
+}
+
+/// Returns the ranges of the records of the image (adjacent records are merged) and the page_offset_base (ELF images only).
+/// Images without metadata are read twice, as the ranges are needed up front (e.g. for the ELF headers).
+fn image_ranges(path: &Path, format: &OutputFormat) -> anyhow::Result<(Vec<Range<u64>>, Option<u64>)> {
+    let mut input = ImageInput::open(path, &[], &[])?;
+    let mut collector = RangeCollector::default();
+    let summary = read_image(input.image()?, format, &[], &mut collector)?;
+    if !summary.end.is_complete() {
+        anyhow::bail!("The image is incomplete: {}.", summary.end);
+    }
+    Ok((collector.ranges, summary.page_offset_base))
+}
+
+/// Collects the ranges of the records of an image.
+#[derive(Default)]
+struct RangeCollector {
+    ranges: Vec<Range<u64>>,
+}
+
+impl ImageVisitor for RangeCollector {
+    fn begin_record(&mut self, range: &Range<u64>) -> anyhow::Result<()> {
+        match self.ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            Some(last) if range.start < last.end =>
+                anyhow::bail!("The record 0x{:x} - 0x{:x} overlaps or precedes the previous record, the image cannot be converted.", range.start, range.end - 1),
+            _ => self.ranges.push(range.clone()),
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, _address: u64, _data: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the data of the image range by range to the format. The holes within the ranges (e.g. the omitted zero blocks of AVML)
+/// are zero filled.
+struct Converter<'a> {
+    format: Box<dyn ImageFormat>,
+    output: &'a mut Pipeline,
+    ranges: &'a [Range<u64>],
+    /// the index of the current range.
+    current: usize,
+    /// the next address of the current range (if the range has been begun).
+    cursor: Option<u64>,
+    /// the algorithms of the range hashes (if the ranges are written to the metadata).
+    range_algorithms: Option<Vec<HashAlgorithm>>,
+    record: Option<RangeRecord>,
+    records: Vec<RangeMetadata>,
+}
+
+impl Converter<'_> {
+    /// Writes the data, which starts at the given address (within the current range).
+    fn write(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()> {
+        if self.cursor.is_none() {
+            let range = &self.ranges[self.current];
+            self.format.begin_range(self.output, range)?;
+            self.record = self.range_algorithms.as_ref().map(|algorithms| RangeRecord::new(range, algorithms));
+            self.cursor = Some(range.start);
+        }
+        self.format.write_data(self.output, address, data)?;
+        if let Some(record) = &mut self.record {
+            record.update(data);
+        }
+        self.cursor = Some(address + data.len() as u64);
+        Ok(())
+    }
+
+    /// Zero fills the current range up to the given address.
+    fn fill(&mut self, end: u64) -> anyhow::Result<()> {
+        let zeros = [0; BUFFER_SIZE];
+        let mut address = self.cursor.unwrap_or(self.ranges[self.current].start);
+        while address < end {
+            let size = (end - address).min(BUFFER_SIZE as u64);
+            self.write(address, &zeros[..size as usize])?;
+            address += size;
+        }
+        Ok(())
+    }
+
+    /// Zero fills the rest of the current range and continues with the next range.
+    fn finish_range(&mut self) -> anyhow::Result<()> {
+        self.fill(self.ranges[self.current].end)?;
+        if let Some(record) = self.record.take() {
+            self.records.push(record.finalize());
+        }
+        self.current += 1;
+        self.cursor = None;
+        Ok(())
+    }
+
+    /// Returns true, if the data of all ranges has been written.
+    fn is_written(&self) -> bool {
+        match self.ranges.get(self.current) {
+            Some(range) => self.current + 1 == self.ranges.len() && self.cursor == Some(range.end),
+            None => true,
+        }
+    }
+
+    /// Zero fills the remaining ranges (which contain no data, e.g. AVML ranges of zero blocks).
+    fn finish(&mut self) -> anyhow::Result<()> {
+        while self.current < self.ranges.len() {
+            self.finish_range()?;
+        }
+        Ok(())
+    }
+}
+
+impl ImageVisitor for Converter<'_> {
+    fn begin_record(&mut self, _range: &Range<u64>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn data(&mut self, mut address: u64, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            while self.ranges.get(self.current).is_some_and(|range| range.end <= address) {
+                self.finish_range()?;
+            }
+            let range = match self.ranges.get(self.current) {
+                Some(range) if range.start <= address && self.cursor.is_none_or(|cursor| cursor <= address) => range.clone(),
+                _ => anyhow::bail!("The data at 0x{address:x} is not part of the ranges of the image (or precedes the previous record)."),
+            };
+            self.fill(address)?;
+            let size = (range.end - address).min(data.len() as u64) as usize;
+            self.write(address, &data[..size])?;
+            address += size as u64;
+            data = &data[size..];
+        }
+        Ok(())
+    }
+}
+
+/// Returns the metadata of the converted image: the metadata of the image (if any) with the new format, compression and hashes.
+fn converted_metadata(
+    args: &Cli,
+    sidecar: Option<Value>,
+    page_offset_base: u64,
+    ranges: Vec<RangeMetadata>,
+    source: ConvertedFrom,
+    digests: &ImageDigests) -> anyhow::Result<Value> {
+
+    let mut metadata = match sidecar {
+        Some(sidecar) => sidecar,
+        None => json!({
+            "tool": TOOL_NAME,
+            "version": env!("CARGO_PKG_VERSION"),
+            "page_offset_base": format!("0x{page_offset_base:x}"),
+            "status": AcquisitionStatus::Completed,
+            "ranges": ranges,
+        }),
+    };
+    let Some(fields) = metadata.as_object_mut() else {
+        anyhow::bail!("The metadata of the image is not a JSON object.");
+    };
+    fields.insert("output_format".to_string(), value_name(&args.output_format).into());
+    fields.insert("compression".to_string(), serde_json::to_value(CompressionParameters::new(args))?);
+    fields.insert("recipients".to_string(), args.recipients.clone().into());
+    fields.insert("image_hashes".to_string(), serde_json::to_value(digests_as_map(&digests.image))?);
+    fields.insert("output_hashes".to_string(), serde_json::to_value(digests_as_map(&digests.output))?);
+    fields.insert("converted_from".to_string(), serde_json::to_value(source)?);
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const RANGES: [Range<u64>; 2] = [0x1000..0x3000, 0x5000..0x6000];
+
+    /// Writes a LiME image of RANGES.
+    fn lime_image(path: &Path) -> Vec<u8> {
+        let image = write_image(&OutputFormat::Lime, &RANGES);
+        std::fs::write(path, &image).unwrap();
+        image
+    }
+
+    fn convert_args(input: PathBuf, output: PathBuf, sidecar: Option<PathBuf>) -> ConvertArgs {
+        ConvertArgs { output, sidecar, input }
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = TestDir::new("convert-round-trips");
+        let lime = dir.path("image.lime");
+        let image = lime_image(&lime);
+        for output_format in [OutputFormat::Raw, OutputFormat::PaddedRaw, OutputFormat::Elf] {
+            let name = value_name(&output_format);
+            let converted = dir.path(&format!("image.{name}"));
+            let metadata = dir.path(&format!("image.{name}.json"));
+            let args = Cli { output_format, metadata: Some(metadata.clone()), ..Cli::default() };
+            convert(&args, &convert_args(lime.clone(), converted.clone(), None)).unwrap();
+
+            let round_trip = dir.path(&format!("round-trip.{name}.lime"));
+            let args = Cli { output_format: OutputFormat::Lime, ..Cli::default() };
+            convert(&args, &convert_args(converted, round_trip.clone(), Some(metadata))).unwrap();
+            assert!(std::fs::read(&round_trip).unwrap() == image, "{name}");
+        }
+    }
+
+    /// Converts the image into the given format (and compression) and back into a LiME image.
+    fn round_trip(dir: &TestDir, image: &Path, output_format: OutputFormat, compression: Compression) -> Vec<u8> {
+        let name = format!("{}.{}", value_name(&output_format), value_name(&compression));
+        let converted = dir.path(&format!("image.{name}"));
+        let args = Cli { output_format, compression, ..Cli::default() };
+        convert(&args, &convert_args(image.to_path_buf(), converted.clone(), None)).unwrap();
+        let round_trip = dir.path(&format!("round-trip.{name}.lime"));
+        let args = Cli { output_format: OutputFormat::Lime, ..Cli::default() };
+        convert(&args, &convert_args(converted, round_trip.clone(), None)).unwrap();
+        std::fs::read(&round_trip).unwrap()
+    }
+
+    #[test]
+    fn compressed_images_round_trip() {
+        let dir = TestDir::new("convert-compressed");
+        let lime = dir.path("image.lime");
+        let image = lime_image(&lime);
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let name = value_name(&compression);
+            assert!(round_trip(&dir, &lime, OutputFormat::Lime, compression) == image, "{name}");
+        }
+    }
+
+    #[test]
+    fn avml_image_round_trips() {
+        let dir = TestDir::new("convert-avml");
+        let lime = dir.path("image.lime");
+        let image = lime_image(&lime);
+        assert!(round_trip(&dir, &lime, OutputFormat::Avml, Compression::None) == image);
+    }
+
+    #[test]
+    fn failed_conversion_keeps_the_output() {
+        let dir = TestDir::new("convert-failed");
+        let lime = dir.path("image.lime");
+        let image = lime_image(&lime);
+        std::fs::write(&lime, &image[..image.len() - 0x100]).unwrap();
+        let output = dir.path("image.raw");
+        std::fs::write(&output, b"previous output").unwrap();
+        let args = Cli { output_format: OutputFormat::Raw, ..Cli::default() };
+        assert!(convert(&args, &convert_args(lime, output.clone(), None)).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+        assert!(!dir.path("image.raw.tmp").exists());
+    }
+
+    #[test]
+    fn output_must_not_be_the_input() {
+        let dir = TestDir::new("convert-same-file");
+        let lime = dir.path("image.lime");
+        let image = lime_image(&lime);
+        let args = Cli { output_format: OutputFormat::Raw, ..Cli::default() };
+        let same_file = dir.0.join(".").join("image.lime");
+        assert!(convert(&args, &convert_args(lime.clone(), same_file, None)).is_err());
+        assert_eq!(std::fs::read(&lime).unwrap(), image);
+        // the partial output would overwrite the input.
+        let partial_input = dir.path("image.tmp");
+        std::fs::write(&partial_input, &image).unwrap();
+        assert!(convert(&args, &convert_args(partial_input.clone(), dir.path("image"), None)).is_err());
+        assert_eq!(std::fs::read(&partial_input).unwrap(), image);
+    }
+}
@@ -0,0 +1,280 @@
+// - STD
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+// - parent
+use super::*;
+
+/// Called with the number of bytes, which have been acquired, and the total number of bytes to acquire.
+pub type ProgressCallback = Box<dyn FnMut(u64, u64) + Send>;
+
+/// Cancels a running acquisition: the acquisition stops after the current chunk and the image is finished regularly
+/// (the metadata is marked as interrupted). Clones share the same state, so the token can be moved to another thread.
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Reports the progress of an acquisition to the (optional) progress callback.
+pub(crate) struct Progress {
+    callback: Option<ProgressCallback>,
+    done: u64,
+    total: u64,
+}
+
+impl Progress {
+    pub(crate) fn new(callback: Option<ProgressCallback>) -> Self {
+        Self { callback, done: 0, total: 0 }
+    }
+
+    pub(crate) fn start(&mut self, total: u64) {
+        self.total = total;
+        self.inc(0);
+    }
+
+    pub(crate) fn inc(&mut self, size: u64) {
+        self.done += size;
+        if let Some(callback) = &mut self.callback {
+            callback(self.done, self.total);
+        }
+    }
+}
+
+/// Acquires the physical memory (or the memory of a single process) into the given sink.
+///
+/// ```no_run
+/// use emdumper::{MemoryDumper, OutputFormat, Compression};
+///
+/// let output = std::fs::File::create("memory.lime.zst")?;
+/// MemoryDumper::new()
+///     .format(OutputFormat::Lime)
+///     .compression(Compression::Zstd)
+///     .progress(|done, total| println!("{done}/{total}"))
+///     .dump(output)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// The eBPF program is attached to this executable, so CAP_SYS_ADMIN is necessary. The log messages of the
+/// eBPF program are only forwarded, if the acquisition runs inside of a tokio runtime.
+pub struct MemoryDumper {
+    args: Cli,
+    format: Option<Box<dyn ImageFormat>>,
+    progress: Option<ProgressCallback>,
+    cancellation: Cancellation,
+}
+
+impl Default for MemoryDumper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDumper {
+    /// Creates a dumper with the defaults of emd: System RAM as LiME image, uncompressed, with zero filled gaps.
+    pub fn new() -> Self {
+        Self::from_args(Cli::default())
+    }
+
+    pub(crate) fn from_args(args: Cli) -> Self {
+        Self {
+            args,
+            format: None,
+            progress: None,
+            cancellation: Cancellation::new(),
+        }
+    }
+
+    /// Dumps only the given physical ranges (end exclusive), which are intersected with the iomem map.
+    pub fn ranges(mut self, ranges: impl IntoIterator<Item = Range<u64>>) -> Self {
+        self.args.ranges = ranges.into_iter().collect();
+        self
+    }
+
+    /// Excludes the given physical ranges (end exclusive).
+    pub fn exclude_ranges(mut self, ranges: impl IntoIterator<Item = Range<u64>>) -> Self {
+        self.args.exclude_ranges = ranges.into_iter().collect();
+        self
+    }
+
+    /// Sets the iomem region types to dump (default is System RAM).
+    pub fn iomem_types(mut self, types: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.iomem_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn format(mut self, output_format: OutputFormat) -> Self {
+        self.args.output_format = output_format;
+        self
+    }
+
+    /// Writes the image with the given format instead of a built-in one (see format).
+    pub fn image_format(mut self, format: impl ImageFormat + 'static) -> Self {
+        self.format = Some(Box::new(format));
+        self
+    }
+
+    pub fn gap_mode(mut self, gap_mode: GapMode) -> Self {
+        self.args.gap_mode = gap_mode;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.args.compression = compression;
+        self
+    }
+
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.args.compress_level = Some(level);
+        self
+    }
+
+    /// Encrypts the output (after compression) to the given age recipients.
+    pub fn recipients(mut self, recipients: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.recipients = recipients.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Hashes the image (the digests are logged and written to the metadata, if any).
+    pub fn hash(mut self, algorithms: impl IntoIterator<Item = HashAlgorithm>) -> Self {
+        self.args.hash = algorithms.into_iter().collect();
+        self
+    }
+
+    /// Writes the JSON acquisition report to the given file.
+    pub fn metadata(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.metadata = Some(path.into());
+        self
+    }
+
+    /// Reads the memory with the given number of worker threads (needs the slot program of emd-ebpf).
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.args.threads = Some(threads);
+        self
+    }
+
+    pub fn read_retries(mut self, retries: u32) -> Self {
+        self.args.read_retries = retries;
+        self
+    }
+
+    /// Dumps the memory of the given process (as ELF core file) instead of the physical memory.
+    pub fn pid(mut self, pid: i32) -> Self {
+        self.args.pid = Some(pid);
+        self
+    }
+
+    pub fn progress(mut self, callback: impl FnMut(u64, u64) + Send + 'static) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    pub fn cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Loads and attaches the eBPF program, acquires the memory into the given sink and detaches the program.
+    /// Any writer can be used as sink, if it is boxed as `Box<dyn Write + Send>`.
+    pub fn dump(self, sink: impl OutputSink + 'static) -> anyhow::Result<()> {
+        let args = &self.args;
+        if args.pid.is_some() && self.format.is_some() {
+            anyhow::bail!("Process memory is always dumped as ELF core file, a custom image format is not supported.");
+        }
+        check_capabilities()?;
+
+        info!("Setting rlimits.");
+        // Bump the memlock rlimit. This is needed for older kernels that don't use the
+        // new memcg based accounting, see https://lwn.net/Articles/837122/
+        let rlim = libc::rlimit {
+            rlim_cur: libc::RLIM_INFINITY,
+            rlim_max: libc::RLIM_INFINITY,
+        };
+        let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+        if ret != 0 {
+            warn!("remove limit on locked memory failed, ret is: {}", ret);
+        }
+
+        info!("Load eBPF program.");
+        // This will include your eBPF object file as raw bytes at compile-time and load it at
+        // runtime.
+        let worker_cpus = args.threads.map(worker_cpus).transpose()?;
+        let mut ebpf = load_ebpf(worker_cpus.as_ref().map_or(0, Vec::len))?;
+
+        // aya_log forwards the messages with a tokio task.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            info!("Initialize eBPF logger.");
+            EbpfLogger::init(&mut ebpf)?;
+        }
+
+        info!("Initialize function.");
+        let program: &mut UProbe = ebpf.program_mut(READ_KERNEL_MEM).unwrap().try_into()?;
+        program.load()?;
+
+        let fn_addr = read_kernel_memory as *const () as usize;
+        let offset = (fn_addr - get_base_addr()?) as u64;
+
+        info!("Attaching program.");
+        program.attach(None, offset, PROC_SELF_EXE, None)?;
+
+        let parallel = match worker_cpus {
+            Some(cpus) => {
+                info!("Attaching program for {} worker threads.", cpus.len());
+                let Some(program) = ebpf.program_mut(READ_KERNEL_MEM_SLOT) else {
+                    anyhow::bail!("The eBPF program does not support --threads.");
+                };
+                let program: &mut UProbe = program.try_into()?;
+                program.load()?;
+                let fn_addr = read_kernel_memory_slot as *const () as usize;
+                let offset = (fn_addr - get_base_addr()?) as u64;
+                program.attach(None, offset, PROC_SELF_EXE, None)?;
+                Some(ParallelReader::new(&mut ebpf, cpus, args.read_retries)?)
+            },
+            None => None,
+        };
+
+        info!("Initializing transport.");
+        let mut transport = Transport::new(&mut ebpf, args.read_retries)?;
+        let mut reader = MemoryReader {
+            transport: &mut transport,
+            parallel: parallel.as_ref(),
+            cancellation: &self.cancellation,
+        };
+        let mut progress = Progress::new(self.progress);
+        let result = match args.pid {
+            Some(pid) => dump_process_memory(args, pid, &mut reader, Box::new(sink), &mut progress),
+            None => {
+                let format = self.format.unwrap_or_else(|| builtin_format(&args.output_format));
+                dump_physical_memory(args, &mut reader, Box::new(sink), format, &mut progress)
+            },
+        };
+
+        // detaches the uprobes.
+        drop(parallel);
+        drop(transport);
+        drop(ebpf);
+        result
+    }
+}
+
+/// Checks, if the process has the necessary capabilities to load the eBPF program (CAP_SYS_ADMIN).
+pub(crate) fn check_capabilities() -> anyhow::Result<()> {
+    match has_cap(None, CapSet::Effective, Capability::CAP_SYS_ADMIN) {
+        Ok(true) => Ok(()),
+        Ok(false) => anyhow::bail!("Missing necessary capabilities (CAP_SYS_ADMIN) - You should try to run emd as root. ;)"),
+        Err(e) => anyhow::bail!("Unable to verify capabilities (You should try to run emd as root): {e}"),
+    }
+}
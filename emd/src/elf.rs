@@ -0,0 +1,66 @@
+// - parent
+use super::*;
+
+/// A PT_LOAD segment of an ELF core file.
+pub(crate) struct ElfSegment {
+    pub(crate) vaddr: u64,
+    pub(crate) paddr: u64,
+    pub(crate) size: u64,
+    pub(crate) flags: u32,
+}
+
+/// Builds the ELF header, the program headers (one PT_NOTE and one PT_LOAD per segment) and the notes.
+/// The segment data is expected to follow directly, in the order of the given segments.
+pub(crate) fn elf_prologue(segments: &[ElfSegment], notes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let phnum = u16::try_from(segments.len() + 1)?;
+
+    let notes_offset = (ELF_HEADER_SIZE + ELF_PROGRAM_HEADER_SIZE * phnum as usize) as u64;
+    // the segment data starts page aligned.
+    let data_offset = (notes_offset + notes.len() as u64).next_multiple_of(ELF_PAGE_SIZE);
+
+    let mut prologue = Vec::with_capacity(data_offset as usize);
+    prologue.extend_from_slice(&ElfHeader::new(phnum).as_bytes());
+    let note_header = ElfProgramHeader {
+        p_type: PT_NOTE,
+        p_offset: notes_offset,
+        p_filesz: notes.len() as u64,
+        ..Default::default()
+    };
+    prologue.extend_from_slice(&note_header.as_bytes());
+
+    let mut p_offset = data_offset;
+    for segment in segments {
+        let load_header = ElfProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: segment.flags,
+            p_offset,
+            p_vaddr: segment.vaddr,
+            p_paddr: segment.paddr,
+            p_filesz: segment.size,
+            p_memsz: segment.size,
+            p_align: 0,
+        };
+        prologue.extend_from_slice(&load_header.as_bytes());
+        p_offset += segment.size;
+    }
+    prologue.extend_from_slice(notes);
+    prologue.resize(data_offset as usize, 0);
+    Ok(prologue)
+}
+
+/// The ELF prologue of a physical memory dump: one PT_LOAD per memory range (mapped at page_offset_base)
+/// and notes with the kernel release, page_offset_base and the emd version.
+pub(crate) fn physical_memory_elf_prologue(memory_range: &[Range<u64>], page_offset_base: u64) -> anyhow::Result<Vec<u8>> {
+    let mut notes = Vec::new();
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_OSRELEASE, get_os_release()?.as_bytes()));
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_PAGE_OFFSET_BASE, &page_offset_base.to_le_bytes()));
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_VERSION, env!("CARGO_PKG_VERSION").as_bytes()));
+
+    let segments: Vec<ElfSegment> = memory_range.iter().map(|range| ElfSegment {
+        vaddr: page_offset_base + range.start,
+        paddr: range.start,
+        size: range.end - range.start,
+        flags: PF_R | PF_W | PF_X,
+    }).collect();
+    elf_prologue(&segments, &notes)
+}
@@ -0,0 +1,147 @@
+// - STD
+use std::{io::{copy, BufRead, BufReader}, str::FromStr};
+
+// - parent
+use super::*;
+
+// - External
+use age::{
+    stream::StreamWriter,
+    x25519, Decryptor, Encryptor, Identity, IdentityFile, Recipient,
+};
+
+use crate::traits::HumanReadable;
+
+/// the beginning of the binary age format (the version line).
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// Encrypts the data to the given age recipients. The age stream is only finished by finish(), so an aborted
+/// acquisition does not leave an encrypted image, which decrypts like a complete one.
+pub(crate) struct AgeSink<W: Write> {
+    writer: Option<StreamWriter<W>>,
+    /// the output, after the age stream has been finished.
+    output: Option<W>,
+}
+
+impl<W: Write> AgeSink<W> {
+    pub(crate) fn new(output: W, recipients: &[String]) -> anyhow::Result<Self> {
+        let recipients = recipients.iter()
+            .map(|recipient| x25519::Recipient::from_str(recipient).map_err(|e| anyhow::anyhow!("Invalid recipient {recipient}: {e}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let encryptor = Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as &dyn Recipient))?;
+        Ok(Self {
+            writer: Some(encryptor.wrap_output(output)?),
+            output: None,
+        })
+    }
+}
+
+impl<W: Write> Write for AgeSink<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.writer {
+            Some(writer) => writer.write(buf),
+            None => Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the age stream has been finished")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match (&mut self.writer, &mut self.output) {
+            (Some(writer), _) => writer.flush(),
+            (None, Some(output)) => output.flush(),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl<W: OutputSink> OutputSink for AgeSink<W> {
+    fn finish(&mut self) -> std::io::Result<()> {
+        if let Some(writer) = self.writer.take() {
+            self.output = Some(writer.finish()?);
+        }
+        match &mut self.output {
+            Some(output) => output.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns true, if the given input is age encrypted (the input is not consumed).
+pub(crate) fn is_encrypted(input: &mut impl BufRead) -> std::io::Result<bool> {
+    Ok(input.fill_buf()?.starts_with(AGE_MAGIC))
+}
+
+/// Decrypts an encrypted image with the identities of the given age identity file.
+pub(crate) fn decrypt(args: &DecryptArgs) -> anyhow::Result<()> {
+    let identities = IdentityFile::from_file(args.identity.to_string_lossy().to_string())?.into_identities()?;
+    let input = BufReader::new(File::open(&args.input)?);
+    let decryptor = Decryptor::new(input)?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref() as &dyn Identity))?;
+
+    let mut output_file = BufWriter::new(File::create(&args.output)?);
+    let decrypted = copy(&mut reader, &mut output_file)?;
+    output_file.flush()?;
+    info!("Decrypted {} to {}.", decrypted.bytes_as_hrb(), args.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::io::Read;
+
+    /// Passes the data to the next stage, but fails to finish (e.g. a failing compression stage).
+    struct FailingFinish(Box<dyn OutputSink>);
+
+    impl Write for FailingFinish {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl OutputSink for FailingFinish {
+        fn finish(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::other("compression failed"))
+        }
+    }
+
+    /// Creates a pipeline, which encrypts into the returned sink, and the identity to decrypt it.
+    fn encrypted_pipeline() -> (Pipeline, SharedSink, x25519::Identity) {
+        let identity = x25519::Identity::generate();
+        let sink = SharedSink::default();
+        let age = AgeSink::new(sink.clone(), &[identity.to_public().to_string()]).unwrap();
+        (Pipeline::new("writer", Box::new(age)).unwrap(), sink, identity)
+    }
+
+    fn decrypt_data(data: &[u8], identity: &x25519::Identity) -> anyhow::Result<Vec<u8>> {
+        let mut reader = Decryptor::new(data)?.decrypt(std::iter::once(identity as &dyn Identity))?;
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn finished_pipeline_decrypts() {
+        let (mut pipeline, sink, identity) = encrypted_pipeline();
+        let data = pattern(0..0x30011);
+        pipeline.write_all(&data).unwrap();
+        pipeline.finish().unwrap();
+        assert!(sink.is_finished());
+        assert_eq!(decrypt_data(&sink.data(), &identity).unwrap(), data);
+    }
+
+    #[test]
+    fn aborted_pipeline_does_not_decrypt() {
+        let (mut pipeline, sink, identity) = encrypted_pipeline();
+        pipeline.prepend("compression", |output| Ok(Box::new(FailingFinish(output)))).unwrap();
+        pipeline.write_all(&pattern(0..0x30011)).unwrap();
+        assert!(pipeline.finish().is_err());
+        assert!(!sink.is_finished());
+        assert!(!sink.data().is_empty());
+        assert!(decrypt_data(&sink.data(), &identity).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+// - STD
+use std::io;
+
+// - parent
+use super::*;
+
+// - External
+use snap::write::FrameEncoder as SnapEncoder;
+
+/// The image, which is passed to ImageFormat::begin_image.
+#[non_exhaustive]
+pub struct ImageInfo<'a> {
+    /// the physical ranges, which will be acquired (in this order).
+    pub ranges: &'a [Range<u64>],
+    /// the virtual address of the kernel's direct mapping of the physical memory.
+    pub page_offset_base: u64,
+}
+
+/// The layout of an image. The acquired memory is passed range by range (in ascending order of the physical
+/// addresses) to the format, which writes it to the output (which is compressed, encrypted and written to the sink afterwards).
+pub trait ImageFormat: Send {
+    /// Called once, before the first range.
+    fn begin_image(&mut self, _output: &mut dyn OutputSink, _image: &ImageInfo) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called before the data of a range. With the gap mode split, a range is passed as several ranges (without the unreadable zones).
+    fn begin_range(&mut self, _output: &mut dyn OutputSink, _range: &Range<u64>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called with the data of the current range, which starts at the given physical address.
+    fn write_data(&mut self, output: &mut dyn OutputSink, _address: u64, data: &[u8]) -> anyhow::Result<()> {
+        output.write_all(data)?;
+        Ok(())
+    }
+
+    /// Called for each unreadable zone. With the gap mode zero, the zone is passed as zeros to write_data afterwards.
+    fn record_gap(&mut self, _output: &mut dyn OutputSink, _gap: &Range<u64>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the end of the data, which has already been announced (e.g. by a LiME header), if the acquisition is
+    /// cancelled at the given address within the given range. The announced data is zero filled, so the image stays valid.
+    fn announced_end(&self, _range: &Range<u64>, address: u64) -> u64 {
+        address
+    }
+
+    /// Called once after the last range (also if the acquisition has been cancelled).
+    fn finish(&mut self, _output: &mut dyn OutputSink) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The ranges are concatenated without any metadata.
+#[derive(Default)]
+pub struct RawFormat;
+
+impl ImageFormat for RawFormat {}
+
+/// The ranges are written at the offset of their physical address, the holes between the ranges are zero filled.
+#[derive(Default)]
+pub struct PaddedRawFormat {
+    offset: u64,
+}
+
+impl ImageFormat for PaddedRawFormat {
+    fn begin_range(&mut self, output: &mut dyn OutputSink, range: &Range<u64>) -> anyhow::Result<()> {
+        if range.start < self.offset {
+            anyhow::bail!("The range 0x{:x} - 0x{:x} overlaps the previous range.", range.start, range.end - 1);
+        }
+        // the size of the hole is known, so it is skipped (e.g. seeked over by a sparse file).
+        output.skip(range.start - self.offset)?;
+        self.offset = range.start;
+        Ok(())
+    }
+
+    fn write_data(&mut self, output: &mut dyn OutputSink, _address: u64, data: &[u8]) -> anyhow::Result<()> {
+        output.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Each range is written as LiME record (a LiME header followed by the data of the range).
+#[derive(Default)]
+pub struct LimeFormat {
+    /// the end of the last record.
+    record_end: Option<u64>,
+}
+
+impl ImageFormat for LimeFormat {
+    fn begin_range(&mut self, output: &mut dyn OutputSink, range: &Range<u64>) -> anyhow::Result<()> {
+        // the end address of LiME headers is inclusive.
+        output.write_all(&LimeHeader::new(range.start, range.end - 1).as_bytes())?;
+        self.record_end = Some(range.end);
+        Ok(())
+    }
+
+    fn announced_end(&self, range: &Range<u64>, address: u64) -> u64 {
+        // the header of a resumed range has been written by the interrupted acquisition.
+        self.record_end.unwrap_or(range.end).max(address)
+    }
+}
+
+/// An ELF core file with one PT_LOAD per range (the headers are written up front).
+#[derive(Default)]
+pub struct ElfFormat {
+    /// the end of the last range.
+    end: u64,
+}
+
+impl ImageFormat for ElfFormat {
+    fn begin_image(&mut self, output: &mut dyn OutputSink, image: &ImageInfo) -> anyhow::Result<()> {
+        output.write_all(&physical_memory_elf_prologue(image.ranges, image.page_offset_base)?)?;
+        self.end = image.ranges.last().map_or(0, |range| range.end);
+        Ok(())
+    }
+
+    fn announced_end(&self, _range: &Range<u64>, address: u64) -> u64 {
+        // the program headers announce all ranges.
+        self.end.max(address)
+    }
+}
+
+/// LiME version 2 records with snappy compressed payloads of at most AVML_MAX_BLOCK_SIZE bytes (like Microsoft AVML).
+#[derive(Default)]
+pub struct AvmlFormat {
+    block: Option<AvmlBlock>,
+    range_end: u64,
+}
+
+impl ImageFormat for AvmlFormat {
+    fn begin_range(&mut self, _output: &mut dyn OutputSink, range: &Range<u64>) -> anyhow::Result<()> {
+        self.range_end = range.end;
+        Ok(())
+    }
+
+    fn write_data(&mut self, output: &mut dyn OutputSink, mut address: u64, mut data: &[u8]) -> anyhow::Result<()> {
+        // the data is split, so no block exceeds AVML_MAX_BLOCK_SIZE (larger records are rejected by the readers).
+        while !data.is_empty() {
+            let block = self.block.get_or_insert_with(|| AvmlBlock::new(address));
+            let size = data.len().min((AVML_MAX_BLOCK_SIZE - block.size) as usize);
+            block.write(&data[..size])?;
+            address += size as u64;
+            data = &data[size..];
+            if block.size >= AVML_MAX_BLOCK_SIZE || address == self.range_end {
+                self.block.take().unwrap().finish(output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, output: &mut dyn OutputSink) -> anyhow::Result<()> {
+        if let Some(block) = self.block.take() {
+            block.finish(output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the built-in format of the given output format.
+pub(crate) fn builtin_format(output_format: &OutputFormat) -> Box<dyn ImageFormat> {
+    match output_format {
+        OutputFormat::Raw => Box::new(RawFormat),
+        OutputFormat::PaddedRaw => Box::new(PaddedRawFormat::default()),
+        OutputFormat::Lime => Box::new(LimeFormat::default()),
+        OutputFormat::Elf => Box::new(ElfFormat::default()),
+        OutputFormat::Avml => Box::new(AvmlFormat::default()),
+    }
+}
+
+/// A single AVML block: a LiME version 2 header, the snappy (framed) compressed data of the block
+/// and the size of the compressed data as trailing u64.
+struct AvmlBlock {
+    start_address: u64,
+    size: u64,
+    all_zero: bool,
+    encoder: SnapEncoder<Vec<u8>>,
+}
+
+impl AvmlBlock {
+    fn new(start_address: u64) -> Self {
+        Self {
+            start_address,
+            size: 0,
+            all_zero: true,
+            encoder: SnapEncoder::new(Vec::new()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.all_zero &= data.iter().all(|byte| *byte == 0);
+        self.size += data.len() as u64;
+        self.encoder.write_all(data)
+    }
+
+    fn finish(self, output: &mut dyn OutputSink) -> anyhow::Result<()> {
+        // like AVML, blocks which only contain zeros are skipped.
+        if self.all_zero {
+            return Ok(());
+        }
+        let compressed = self.encoder.into_inner().map_err(|e| e.into_error())?;
+        // the end address of AVML headers is inclusive.
+        let header = LimeHeader::new_avml(self.start_address, self.start_address + self.size - 1);
+        output.write_all(&header.as_bytes())?;
+        output.write_all(&compressed)?;
+        output.write_all(&(compressed.len() as u64).to_le_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+// - STD
+use std::sync::{Arc, Mutex};
+
+// - parent
+use super::*;
+
+// - External
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha1 => "SHA1",
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Blake3 => "BLAKE3",
+        }
+    }
+}
+
+/// Returns the chosen hash algorithms or sha256, if none was chosen (used by the metadata and the split manifest).
+pub(crate) fn hash_algorithms_or_default(args: &Cli) -> Vec<HashAlgorithm> {
+    if args.hash.is_empty() {
+        vec![HashAlgorithm::Sha256]
+    } else {
+        args.hash.clone()
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => { hasher.update(data); },
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// The hashers of a single stream. Clones share the same hashers, so the digests can be read
+/// after the writer chain (which owns the [HashWriter]) was finished and dropped.
+#[derive(Clone)]
+pub(crate) struct StreamHashes {
+    hashers: Arc<Mutex<Vec<(HashAlgorithm, Hasher)>>>,
+}
+
+impl StreamHashes {
+    pub(crate) fn new(algorithms: &[HashAlgorithm]) -> Self {
+        let hashers = algorithms.iter().map(|algorithm| (*algorithm, Hasher::new(*algorithm))).collect();
+        Self {
+            hashers: Arc::new(Mutex::new(hashers)),
+        }
+    }
+
+    pub(crate) fn update(&self, data: &[u8]) {
+        for (_, hasher) in self.hashers.lock().unwrap().iter_mut() {
+            hasher.update(data);
+        }
+    }
+
+    /// Finalizes the hashers and returns the hex encoded digests.
+    pub(crate) fn finalize(&self) -> Vec<(HashAlgorithm, String)> {
+        let hashers = std::mem::take(&mut *self.hashers.lock().unwrap());
+        hashers.into_iter().map(|(algorithm, hasher)| (algorithm, hex_encode(&hasher.finalize()))).collect()
+    }
+}
+
+/// Passes all data through to the inner writer and hashes what was written.
+pub(crate) struct HashWriter<W: Write> {
+    pub(crate) inner: W,
+    hashes: StreamHashes,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub(crate) fn new(inner: W, hashes: StreamHashes) -> Self {
+        Self { inner, hashes }
+    }
+
+    /// Hashes the given number of zeros, which are skipped (see OutputSink::skip).
+    pub(crate) fn hash_zeros(&self, len: u64) {
+        let mut remaining = len;
+        while remaining > 0 {
+            let size = remaining.min(ZERO_BUFFER.len() as u64) as usize;
+            self.hashes.update(&ZERO_BUFFER[..size]);
+            remaining -= size as u64;
+        }
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hashes.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes all data, which is read from the inner reader.
+pub(crate) struct HashReader<R: Read> {
+    inner: R,
+    hashes: StreamHashes,
+}
+
+impl<R: Read> HashReader<R> {
+    pub(crate) fn new(inner: R, hashes: StreamHashes) -> Self {
+        Self { inner, hashes }
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hashes.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// The hashes of the (uncompressed) image and - if compression or encryption is used - of the written output.
+pub(crate) struct ImageHashes {
+    pub(crate) image: StreamHashes,
+    pub(crate) output: Option<StreamHashes>,
+}
+
+impl ImageHashes {
+    pub(crate) fn new(args: &Cli) -> Self {
+        let output = match args.compression {
+            Compression::None if args.recipients.is_empty() => None,
+            _ => Some(StreamHashes::new(&args.hash)),
+        };
+        Self {
+            image: StreamHashes::new(&args.hash),
+            output,
+        }
+    }
+
+    /// Finalizes the hashers. Must be called after the writer chain was finished.
+    pub(crate) fn finalize(&self) -> ImageDigests {
+        ImageDigests {
+            image: self.image.finalize(),
+            output: self.output.as_ref().map(|output| output.finalize()).unwrap_or_default(),
+        }
+    }
+}
+
+pub(crate) struct ImageDigests {
+    pub(crate) image: Vec<(HashAlgorithm, String)>,
+    pub(crate) output: Vec<(HashAlgorithm, String)>,
+}
+
+impl ImageDigests {
+    /// Prints the digests and writes them to the hash file (if set).
+    pub(crate) fn report(&self, args: &Cli) -> anyhow::Result<()> {
+        let mut lines = Vec::new();
+        for (algorithm, digest) in &self.image {
+            lines.push(format!("{} (image) = {digest}", algorithm.name()));
+        }
+        for (algorithm, digest) in &self.output {
+            lines.push(format!("{} (output) = {digest}", algorithm.name()));
+        }
+        for line in &lines {
+            info!("{line}");
+        }
+        if let Some(hash_file) = &args.hash_file {
+            let mut file = File::create(hash_file)?;
+            for line in &lines {
+                writeln!(file, "{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
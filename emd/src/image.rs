@@ -0,0 +1,511 @@
+// - STD
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+};
+
+// - parent
+use super::*;
+
+// - External
+use snap::read::FrameDecoder as SnapDecoder;
+
+/// the maximum size of the notes of an ELF image, which are read (e.g. /proc/<pid>/maps of a process dump).
+const MAX_NOTES_SIZE: u64 = 0x1000000;
+
+/// Receives the content of an existing image (see read_image), in the order of the image.
+pub(crate) trait ImageVisitor {
+    /// Called before the data of a record (a LiME record, a PT_LOAD segment or a range of a raw image).
+    fn begin_record(&mut self, range: &Range<u64>) -> anyhow::Result<()>;
+
+    /// Called with the data of the current record, which starts at the given physical address.
+    fn data(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()>;
+}
+
+/// The (uncompressed) image, as far as it could be parsed.
+pub(crate) struct ImageSummary {
+    pub(crate) end: ImageEnd,
+    /// the number of bytes, which have been parsed.
+    pub(crate) size: u64,
+    /// the page_offset_base of the emd notes (ELF images only).
+    pub(crate) page_offset_base: Option<u64>,
+}
+
+/// How the parsing of an image ended. The offsets are offsets of the uncompressed image.
+pub(crate) enum ImageEnd {
+    Complete,
+    /// the image ends within the record (or the header) at the given offset.
+    Truncated { offset: u64, range: Option<Range<u64>> },
+    /// there is no valid header at the given offset (the rest of the image is not parsed).
+    Malformed { offset: u64, reason: String },
+    /// the image (e.g. the compressed stream) could not be read at the given offset.
+    Unreadable { offset: u64, error: String },
+    /// the image continues after the last range or segment.
+    TrailingData { offset: u64 },
+}
+
+impl ImageEnd {
+    pub(crate) fn is_complete(&self) -> bool {
+        matches!(self, ImageEnd::Complete)
+    }
+}
+
+impl fmt::Display for ImageEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageEnd::Complete => write!(f, "the image is complete"),
+            ImageEnd::Truncated { offset, range: Some(range) } =>
+                write!(f, "the image is truncated within the record 0x{:x} - 0x{:x} at offset {offset}", range.start, range.end - 1),
+            ImageEnd::Truncated { offset, range: None } => write!(f, "the image is truncated at offset {offset}"),
+            ImageEnd::Malformed { offset, reason } => write!(f, "malformed header at offset {offset}: {reason}"),
+            ImageEnd::Unreadable { offset, error } => write!(f, "the image could not be read at offset {offset}: {error}"),
+            ImageEnd::TrailingData { offset } => write!(f, "unexpected data after the last record at offset {offset}"),
+        }
+    }
+}
+
+/// Detects the format of an (uncompressed) image by its first bytes (the input is not consumed).
+/// Images without LiME or ELF header are detected as raw.
+pub(crate) fn detect_format(input: &mut impl BufRead) -> io::Result<OutputFormat> {
+    let head = input.fill_buf()?;
+    Ok(match head.get(0..4) {
+        Some(magic) if magic == LIME_MAGIC.to_le_bytes() => OutputFormat::Lime,
+        Some(magic) if magic == AVML_MAGIC.to_le_bytes() => OutputFormat::Avml,
+        Some(magic) if magic == ELF_MAGIC => OutputFormat::Elf,
+        _ => OutputFormat::Raw,
+    })
+}
+
+/// Returns the format of an image with the given detected format: raw images are padded, if their metadata says so.
+pub(crate) fn image_format(detected: OutputFormat, metadata: Option<&ImageMetadata>) -> OutputFormat {
+    match (&detected, metadata) {
+        (OutputFormat::Raw, Some(metadata)) if metadata.output_format == value_name(&OutputFormat::PaddedRaw) => OutputFormat::PaddedRaw,
+        _ => detected,
+    }
+}
+
+/// An existing image file, which is hashed while it is read: the file itself (if it is compressed) and the uncompressed image.
+pub(crate) struct ImageInput {
+    input: BufReader<HashReader<File>>,
+    pub(crate) compression: Compression,
+    output_hashes: StreamHashes,
+    image_hashes: StreamHashes,
+}
+
+impl ImageInput {
+    /// Opens the given image and detects its compression. Encrypted images have to be decrypted first (see emd decrypt).
+    pub(crate) fn open(path: &Path, output_algorithms: &[HashAlgorithm], image_algorithms: &[HashAlgorithm]) -> anyhow::Result<Self> {
+        let file = File::open(path).map_err(|e| anyhow::anyhow!("Could not open {}: {e}", path.display()))?;
+        let output_hashes = StreamHashes::new(output_algorithms);
+        let mut input = BufReader::new(HashReader::new(file, output_hashes.clone()));
+        if is_encrypted(&mut input)? {
+            anyhow::bail!("The image {} is encrypted, decrypt it first (see emd decrypt).", path.display());
+        }
+        let compression = detect_compression(&mut input)?;
+        Ok(Self {
+            input,
+            compression,
+            output_hashes,
+            image_hashes: StreamHashes::new(image_algorithms),
+        })
+    }
+
+    /// Returns the uncompressed image (see detect_format and read_image).
+    pub(crate) fn image(&mut self) -> io::Result<BufReader<HashReader<Box<dyn Read + Send + '_>>>> {
+        let decompressed = decompression_reader(&self.compression, &mut self.input)?;
+        Ok(BufReader::new(HashReader::new(decompressed, self.image_hashes.clone())))
+    }
+
+    /// Reads the rest of the file and returns the digests of the image and - if the image is compressed - of the file.
+    pub(crate) fn finish(mut self) -> ImageDigests {
+        if let Err(e) = io::copy(&mut self.input, &mut io::sink()) {
+            debug!("Could not read the rest of the image: {e}");
+        }
+        let output = match self.compression {
+            Compression::None => Vec::new(),
+            _ => self.output_hashes.finalize(),
+        };
+        ImageDigests { image: self.image_hashes.finalize(), output }
+    }
+}
+
+/// Parses the given (uncompressed) image and passes its records to the visitor. Raw images contain no addresses,
+/// so the ranges of (padded) raw images have to be passed (without ranges, the data of a raw image is only read).
+/// Errors of the visitor are returned, problems of the image are reported by the summary.
+pub(crate) fn read_image(
+    input: impl Read,
+    format: &OutputFormat,
+    raw_ranges: &[Range<u64>],
+    visitor: &mut dyn ImageVisitor) -> anyhow::Result<ImageSummary> {
+
+    let mut parser = ImageParser {
+        input,
+        offset: 0,
+        buffer: vec![0; MAX_QUEUE_SIZE],
+        page_offset_base: None,
+    };
+    let result = match format {
+        OutputFormat::Raw => parser.read_raw(raw_ranges, visitor),
+        OutputFormat::PaddedRaw => parser.read_padded_raw(raw_ranges, visitor),
+        OutputFormat::Lime | OutputFormat::Avml => parser.read_lime(visitor),
+        OutputFormat::Elf => parser.read_elf(visitor),
+    };
+    let end = match result {
+        Ok(()) => ImageEnd::Complete,
+        Err(Stop::End(end)) => end,
+        Err(Stop::Error(e)) => return Err(e),
+    };
+    Ok(ImageSummary { end, size: parser.offset, page_offset_base: parser.page_offset_base })
+}
+
+/// Stops the parsing: either the image ends (regularly or not) or the visitor failed.
+enum Stop {
+    End(ImageEnd),
+    Error(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Stop {
+    fn from(e: anyhow::Error) -> Self {
+        Stop::Error(e)
+    }
+}
+
+struct ImageParser<R> {
+    input: R,
+    offset: u64,
+    buffer: Vec<u8>,
+    page_offset_base: Option<u64>,
+}
+
+impl<R: Read> Read for ImageParser<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.input.read(buf)?;
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read> ImageParser<R> {
+    fn read_raw(&mut self, ranges: &[Range<u64>], visitor: &mut dyn ImageVisitor) -> Result<(), Stop> {
+        if ranges.is_empty() {
+            let result = io::copy(self, &mut io::sink());
+            return result.map(|_| ()).map_err(|e| read_error(self.offset, None, e));
+        }
+        for range in ranges {
+            visitor.begin_record(range)?;
+            self.copy_record(range, self.offset, false, visitor)?;
+        }
+        self.expect_end()
+    }
+
+    fn read_padded_raw(&mut self, ranges: &[Range<u64>], visitor: &mut dyn ImageVisitor) -> Result<(), Stop> {
+        for range in ranges {
+            // the holes between the ranges are zero filled.
+            self.skip_to(range.start)?;
+            visitor.begin_record(range)?;
+            self.copy_record(range, range.start, false, visitor)?;
+        }
+        self.expect_end()
+    }
+
+    fn read_lime(&mut self, visitor: &mut dyn ImageVisitor) -> Result<(), Stop> {
+        loop {
+            let offset = self.offset;
+            let mut bytes = [0u8; LIME_HEADER_SIZE as usize];
+            match read_full(self, &mut bytes).map_err(|e| read_error(offset, None, e))? {
+                0 => return Ok(()),
+                size if size < bytes.len() => return Err(Stop::End(ImageEnd::Truncated { offset, range: None })),
+                _ => (),
+            }
+            let header = LimeHeader::from_bytes(&bytes);
+            let malformed = |reason| Stop::End(ImageEnd::Malformed { offset, reason });
+            // the headers are validated like by LimeReader, which needs a seekable image.
+            let range = lime_record_range(&header).map_err(malformed)?;
+            visitor.begin_record(&range)?;
+            if header.header_version == LIME_HEADER_VERSION {
+                self.copy_record(&range, offset, false, visitor)?;
+                continue;
+            }
+
+            // version 2: the snappy frames are followed by their size.
+            let data_offset = self.offset;
+            self.copy_record(&range, offset, true, visitor)?;
+            let compressed_size = self.offset - data_offset;
+            let mut trailer = [0u8; 8];
+            read_exact(self, &mut trailer, offset, Some(&range))?;
+            if u64::from_le_bytes(trailer) != compressed_size {
+                return Err(malformed(format!("the size of the snappy frames is {compressed_size}, but {} is recorded", u64::from_le_bytes(trailer))));
+            }
+        }
+    }
+
+    fn read_elf(&mut self, visitor: &mut dyn ImageVisitor) -> Result<(), Stop> {
+        let mut bytes = [0u8; ELF_HEADER_SIZE];
+        read_exact(self, &mut bytes, 0, None)?;
+        if bytes[4] != ELF_CLASS_64 || bytes[5] != ELF_DATA_LSB {
+            return Err(Stop::End(ImageEnd::Malformed { offset: 0, reason: "only little endian ELF64 files are supported".to_string() }));
+        }
+        let header = ElfHeader::from_bytes(&bytes);
+        if header.e_type != ELF_TYPE_CORE {
+            return Err(Stop::End(ImageEnd::Malformed { offset: 0, reason: format!("not an ELF core file (type {})", header.e_type) }));
+        }
+        self.skip_to(header.e_phoff)?;
+        let mut program_headers = Vec::with_capacity(header.e_phnum as usize);
+        for _ in 0..header.e_phnum {
+            let offset = self.offset;
+            let mut bytes = [0u8; ELF_PROGRAM_HEADER_SIZE];
+            read_exact(self, &mut bytes, offset, None)?;
+            program_headers.push(ElfProgramHeader::from_bytes(&bytes));
+        }
+        // the image is read sequentially, so the segments are visited in the order of the file.
+        program_headers.retain(|header| matches!(header.p_type, PT_LOAD | PT_NOTE) && header.p_filesz > 0);
+        program_headers.sort_by_key(|header| header.p_offset);
+        for header in program_headers {
+            self.skip_to(header.p_offset)?;
+            if header.p_type == PT_NOTE {
+                self.read_notes(&header)?;
+                continue;
+            }
+            let Some(end) = header.p_paddr.checked_add(header.p_filesz) else {
+                return Err(Stop::End(ImageEnd::Malformed { offset: header.p_offset, reason: "invalid segment size".to_string() }));
+            };
+            let range = header.p_paddr..end;
+            visitor.begin_record(&range)?;
+            self.copy_record(&range, header.p_offset, false, visitor)?;
+        }
+        self.expect_end()
+    }
+
+    /// Reads the notes of the given PT_NOTE segment. The size of the segment is not trusted: the notes are read up to
+    /// the end of the image and larger notes than MAX_NOTES_SIZE are skipped.
+    fn read_notes(&mut self, header: &ElfProgramHeader) -> Result<(), Stop> {
+        if header.p_filesz > MAX_NOTES_SIZE {
+            let Some(end) = header.p_offset.checked_add(header.p_filesz) else {
+                return Err(Stop::End(ImageEnd::Malformed { offset: header.p_offset, reason: "invalid segment size".to_string() }));
+            };
+            warn!("The notes at offset {} are skipped ({} bytes).", header.p_offset, header.p_filesz);
+            return self.skip_to(end);
+        }
+        let mut notes = Vec::new();
+        let read = self.take(header.p_filesz).read_to_end(&mut notes).map_err(|e| read_error(header.p_offset, None, e))?;
+        if (read as u64) < header.p_filesz {
+            return Err(Stop::End(ImageEnd::Truncated { offset: header.p_offset, range: None }));
+        }
+        self.page_offset_base = page_offset_base_note(&notes);
+        Ok(())
+    }
+
+    /// Passes the data of the record at the given offset to the visitor. The data of version 2 LiME records is snappy compressed.
+    fn copy_record(&mut self, range: &Range<u64>, offset: u64, compressed: bool, visitor: &mut dyn ImageVisitor) -> Result<(), Stop> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let result = match compressed {
+            true => copy_data(&mut SnapDecoder::new(&mut *self), &mut buffer, range, offset, visitor),
+            false => copy_data(self, &mut buffer, range, offset, visitor),
+        };
+        self.buffer = buffer;
+        result
+    }
+
+    /// Skips the input up to the given offset.
+    fn skip_to(&mut self, offset: u64) -> Result<(), Stop> {
+        if offset < self.offset {
+            return Err(Stop::End(ImageEnd::Malformed { offset, reason: "overlapping headers or segments".to_string() }));
+        }
+        let start = self.offset;
+        let skipped = io::copy(&mut self.take(offset - start), &mut io::sink()).map_err(|e| read_error(start, None, e))?;
+        if skipped < offset - start {
+            return Err(Stop::End(ImageEnd::Truncated { offset: start, range: None }));
+        }
+        Ok(())
+    }
+
+    fn expect_end(&mut self) -> Result<(), Stop> {
+        let offset = self.offset;
+        match read_full(self, &mut [0u8; 1]).map_err(|e| read_error(offset, None, e))? {
+            0 => Ok(()),
+            _ => Err(Stop::End(ImageEnd::TrailingData { offset })),
+        }
+    }
+}
+
+fn copy_data(
+    input: &mut impl Read,
+    buffer: &mut [u8],
+    range: &Range<u64>,
+    offset: u64,
+    visitor: &mut dyn ImageVisitor) -> Result<(), Stop> {
+
+    let mut address = range.start;
+    while address < range.end {
+        let size = (range.end - address).min(buffer.len() as u64) as usize;
+        let read = read_full(input, &mut buffer[..size]).map_err(|e| read_error(offset, Some(range), e))?;
+        visitor.data(address, &buffer[..read])?;
+        if read < size {
+            return Err(Stop::End(ImageEnd::Truncated { offset, range: Some(range.clone()) }));
+        }
+        address += read as u64;
+    }
+    Ok(())
+}
+
+/// Reads until the buffer is full or the input ends. Returns the number of bytes, which have been read.
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match input.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+/// Reads the (header) bytes of the record at the given offset.
+fn read_exact(input: &mut impl Read, buf: &mut [u8], offset: u64, range: Option<&Range<u64>>) -> Result<(), Stop> {
+    let size = read_full(input, buf).map_err(|e| read_error(offset, range, e))?;
+    if size < buf.len() {
+        return Err(Stop::End(ImageEnd::Truncated { offset, range: range.cloned() }));
+    }
+    Ok(())
+}
+
+fn read_error(offset: u64, range: Option<&Range<u64>>, e: io::Error) -> Stop {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => Stop::End(ImageEnd::Truncated { offset, range: range.cloned() }),
+        _ => Stop::End(ImageEnd::Unreadable { offset, error: e.to_string() }),
+    }
+}
+
+/// Returns the page_offset_base of the emd notes (see physical_memory_elf_prologue), if any.
+fn page_offset_base_note(mut notes: &[u8]) -> Option<u64> {
+    let u32_at = |notes: &[u8], offset: usize| notes.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    while let (Some(namesz), Some(descsz), Some(n_type)) = (u32_at(notes, 0), u32_at(notes, 4), u32_at(notes, 8)) {
+        let (namesz, descsz) = (namesz as usize, descsz as usize);
+        let desc_offset = 12 + namesz.next_multiple_of(4);
+        let name = notes.get(12..12 + namesz)?;
+        let desc = notes.get(desc_offset..desc_offset + descsz)?;
+        if name.strip_suffix(&[0]) == Some(EMD_NOTE_NAME.as_bytes()) && n_type == EMD_NOTE_PAGE_OFFSET_BASE {
+            return Some(u64::from_le_bytes(desc.try_into().ok()?));
+        }
+        notes = notes.get(desc_offset + descsz.next_multiple_of(4)..)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const RANGES: [Range<u64>; 2] = [0x1000..0x3000, 0x5000..0x6000];
+
+    fn image(output_format: &OutputFormat) -> Vec<u8> {
+        write_image(output_format, &RANGES)
+    }
+
+    /// Returns the offset of the first program header of the given type.
+    fn program_header_offset(image: &[u8], p_type: u32) -> usize {
+        (0..).map(|index| ELF_HEADER_SIZE + index * ELF_PROGRAM_HEADER_SIZE)
+            .find(|&offset| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap()) == p_type)
+            .unwrap()
+    }
+
+    #[test]
+    fn good_images() {
+        for output_format in [OutputFormat::Lime, OutputFormat::Avml, OutputFormat::Elf] {
+            let (summary, records) = read(&image(&output_format), &output_format);
+            assert!(summary.end.is_complete(), "{}: {}", value_name(&output_format), summary.end);
+            assert_eq!(records.ranges, RANGES, "{}", value_name(&output_format));
+            assert_eq!(records.data, RANGES.iter().flat_map(|range| pattern(range.clone())).collect::<Vec<u8>>());
+        }
+        let (summary, _) = read(&image(&OutputFormat::Elf), &OutputFormat::Elf);
+        assert_eq!(summary.page_offset_base, Some(TEST_PAGE_OFFSET_BASE));
+    }
+
+    #[test]
+    fn truncated_lime_image() {
+        let mut image = image(&OutputFormat::Lime);
+        image.truncate(image.len() - 0x100);
+        let (summary, records) = read(&image, &OutputFormat::Lime);
+        let second_record = LIME_HEADER_SIZE + 0x2000;
+        assert!(matches!(summary.end, ImageEnd::Truncated { offset, range: Some(ref range) } if offset == second_record && *range == RANGES[1]),
+            "{}", summary.end);
+        assert_eq!(records.ranges, RANGES);
+
+        image.truncate(second_record as usize + 0x10);
+        let (summary, _) = read(&image, &OutputFormat::Lime);
+        assert!(matches!(summary.end, ImageEnd::Truncated { offset, range: None } if offset == second_record), "{}", summary.end);
+    }
+
+    #[test]
+    fn malformed_lime_header() {
+        let mut image = image(&OutputFormat::Lime);
+        let second_record = (LIME_HEADER_SIZE + 0x2000) as usize;
+        image[second_record] ^= 0xff;
+        let (summary, records) = read(&image, &OutputFormat::Lime);
+        assert!(matches!(summary.end, ImageEnd::Malformed { offset, .. } if offset == second_record as u64), "{}", summary.end);
+        assert_eq!(records.ranges, RANGES[..1]);
+    }
+
+    #[test]
+    fn avml_blocks_do_not_exceed_the_maximum_size() {
+        let range = 0..AVML_MAX_BLOCK_SIZE + 0x1000;
+        let data = pattern(range.clone());
+        let mut format = builtin_format(&OutputFormat::Avml);
+        let mut output = Vec::new();
+        format.begin_image(&mut output, &ImageInfo { ranges: std::slice::from_ref(&range), page_offset_base: 0 }).unwrap();
+        format.begin_range(&mut output, &range).unwrap();
+        // the writes do not add up to the maximum block size.
+        for (index, chunk) in data.chunks(0x3000).enumerate() {
+            format.write_data(&mut output, (index * 0x3000) as u64, chunk).unwrap();
+        }
+        format.finish(&mut output).unwrap();
+        let (summary, records) = read(&output, &OutputFormat::Avml);
+        assert!(summary.end.is_complete(), "{}", summary.end);
+        assert_eq!(records.ranges, [0..AVML_MAX_BLOCK_SIZE, AVML_MAX_BLOCK_SIZE..range.end]);
+        assert!(records.data == data);
+    }
+
+    #[test]
+    fn elf_with_bad_program_header() {
+        let elf = image(&OutputFormat::Elf);
+        let load = program_header_offset(&elf, PT_LOAD);
+        // the segment overlaps the headers.
+        let mut overlapping = elf.clone();
+        overlapping[load + 8..load + 16].copy_from_slice(&0u64.to_le_bytes());
+        let (summary, _) = read(&overlapping, &OutputFormat::Elf);
+        assert!(matches!(summary.end, ImageEnd::Malformed { .. }), "{}", summary.end);
+
+        // the end of the segment is behind u64::MAX.
+        let mut overflowing = elf;
+        overflowing[load + 32..load + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+        let (summary, _) = read(&overflowing, &OutputFormat::Elf);
+        assert!(matches!(summary.end, ImageEnd::Malformed { ref reason, .. } if reason == "invalid segment size"), "{}", summary.end);
+    }
+
+    #[test]
+    fn elf_with_oversized_notes() {
+        // the notes are not allocated up front, the image ends within them.
+        let mut image = image(&OutputFormat::Elf);
+        let note = program_header_offset(&image, PT_NOTE);
+        image[note + 32..note + 40].copy_from_slice(&(MAX_NOTES_SIZE - 1).to_le_bytes());
+        let (summary, _) = read(&image, &OutputFormat::Elf);
+        assert!(matches!(summary.end, ImageEnd::Truncated { .. }), "{}", summary.end);
+
+        image[note + 32..note + 40].copy_from_slice(&u64::MAX.to_le_bytes());
+        let (summary, _) = read(&image, &OutputFormat::Elf);
+        assert!(!summary.end.is_complete());
+    }
+
+    #[test]
+    fn elf_which_is_no_core_file() {
+        let mut image = image(&OutputFormat::Elf);
+        // ET_EXEC
+        image[16..18].copy_from_slice(&2u16.to_le_bytes());
+        let (summary, records) = read(&image, &OutputFormat::Elf);
+        assert!(matches!(summary.end, ImageEnd::Malformed { offset: 0, .. }), "{}", summary.end);
+        assert!(records.ranges.is_empty());
+    }
+}
@@ -0,0 +1,30 @@
+// - parent
+use super::*;
+
+// - External
+use tokio::signal::unix::{SignalKind, signal};
+
+/// exit code of an interrupted acquisition (like a shell reports SIGINT).
+pub(crate) const EXIT_CODE_INTERRUPTED: i32 = 130;
+
+/// Handles SIGINT and SIGTERM: the first signal cancels the acquisition, which stops after the current chunk
+/// (the image is finished regularly), the second one aborts immediately.
+pub(crate) fn install_signal_handler(cancellation: Cancellation) -> anyhow::Result<()> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sigint.recv() => (),
+                _ = sigterm.recv() => (),
+            }
+            if cancellation.is_cancelled() {
+                error!("Aborting immediately.");
+                std::process::exit(EXIT_CODE_INTERRUPTED);
+            }
+            cancellation.cancel();
+            warn!("Interrupted. Finishing the image after the current chunk (send the signal again to abort immediately).");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,481 @@
+// - STD
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Write, stdout},
+     ops::Range, path::{Path, PathBuf}
+};
+
+// - modules
+mod address_calculation;
+mod compression;
+mod convert;
+mod dumper;
+mod elf;
+mod encryption;
+mod format;
+mod hashing;
+mod image;
+mod interrupt;
+mod memory_dump;
+mod metadata;
+mod network;
+mod parallel;
+mod pipeline;
+mod process_dump;
+mod resume;
+mod sink;
+mod split;
+#[cfg(test)]
+mod test_util;
+mod tls;
+mod traits;
+mod transport;
+mod verify;
+
+// - re-exports
+use address_calculation::*;
+use compression::*;
+use convert::*;
+pub use dumper::*;
+use elf::*;
+use encryption::*;
+pub use format::*;
+pub use hashing::HashAlgorithm;
+use hashing::*;
+use image::*;
+use interrupt::*;
+use memory_dump::*;
+use metadata::*;
+use network::*;
+use parallel::*;
+use pipeline::*;
+use process_dump::*;
+use resume::*;
+pub use sink::*;
+use split::*;
+use transport::*;
+use tls::*;
+use verify::*;
+
+// - External
+use aya::{programs::UProbe, Ebpf};
+use aya::maps::{MapData, Queue};
+use aya_log::EbpfLogger;
+use clap::{
+    ArgGroup, Args, Parser, Subcommand, ValueEnum
+};
+use emd_common::*;
+use indicatif::{ProgressBar, MultiProgress, ProgressStyle, ProgressDrawTarget};
+use indicatif_log_bridge::LogWrapper;
+use log::{LevelFilter, info, debug, warn, error};
+use lz4_flex::frame::FrameEncoder as Lz4Encoder;
+use procfs::process::{Process, MMPermissions};
+use zstd::stream::Encoder as ZstdEncoder;
+use caps::{has_cap, CapSet, Capability};
+
+
+/// the name of the tool (in the metadata and in the log).
+const TOOL_NAME: &str = "emd";
+
+/// The command line of emd (see run).
+#[derive(Parser)]
+#[clap(name=TOOL_NAME, about, version, author, subcommand_negates_reqs=true, group(ArgGroup::new("out").args(&["output", "stdout", "network"]).required(true)))]
+pub struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// sets the target file (where your memory will be dumped to).
+    #[clap(short='o', long="outputfile")]
+    output: Option<PathBuf>,
+
+    /// sets the target output to stdout (conflicts with --outputfile)
+    #[clap(short='s', long="stdout")]
+    stdout: bool,
+
+    /// splits the output file into segments of the given size (e.g. 4G), named <outputfile>.000, <outputfile>.001, ...
+    #[clap(long="split-size", requires="output", value_parser=parse_size)]
+    split_size: Option<u64>,
+
+    /// continues an interrupted acquisition (raw and lime only), based on the partial output and <outputfile>.checkpoint.
+    #[clap(long="resume", requires="output", conflicts_with="pid")]
+    resume: bool,
+
+    /// sends the memory to a remote collector (see emd listen), e.g. 192.168.0.10:4444
+    #[clap(short='n', long="network")]
+    network: Option<String>,
+
+    /// encrypts the network connection with TLS (needs --tls-ca and/or --tls-pin).
+    #[clap(long="tls", requires="network")]
+    tls: bool,
+
+    /// sets the CA certificate(s) (PEM), which are used to verify the collector.
+    #[clap(long="tls-ca", requires="tls")]
+    tls_ca: Option<PathBuf>,
+
+    /// pins the SHA-256 fingerprint of the collector's certificate.
+    #[clap(long="tls-pin", requires="tls")]
+    tls_pin: Option<String>,
+
+    /// sets the client certificate (PEM), to authenticate against the collector.
+    #[clap(long="tls-cert", requires_all=["tls", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// sets the private key (PEM) of the client certificate.
+    #[clap(long="tls-key", requires="tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// sets the server name, which is verified against the collector's certificate - default is the ip address.
+    #[clap(long="tls-server-name", requires="tls")]
+    tls_server_name: Option<String>,
+
+    /// sets the log level - default is info.
+    #[clap(short='l', long="loglevel", global=true, required=false, value_enum, default_value="info")]
+    log_level: LogLevel,
+
+    /// sets the compression.
+    #[clap(short='c', long="compress", global=true, required=false, value_enum, default_value="none")]
+    compression: Compression,
+
+    /// sets the compression level - default is 3 (zstd) or 6 (gzip, xz).
+    #[clap(long="compress-level", global=true, allow_negative_numbers=true)]
+    compress_level: Option<i32>,
+
+    /// sets the number of zstd worker threads (zstd only) - default is single-threaded compression.
+    #[clap(long="compress-threads", global=true)]
+    compress_threads: Option<u32>,
+
+    /// enables the long distance matching of zstd (better ratio for large, repetitive images).
+    #[clap(long="zstd-long", global=true)]
+    zstd_long: bool,
+
+    /// sets the window size of zstd as power of 2, e.g. 27 (decompressing windows above 27 needs --long=<N> with the zstd cli).
+    #[clap(long="zstd-window-log", global=true, value_parser=clap::value_parser!(u32).range(10..=31))]
+    zstd_window_log: Option<u32>,
+
+    /// encrypts the output (after compression) to the given age recipient (e.g. age1...). Can be set multiple times.
+    #[clap(short='r', long="recipient", global=true)]
+    recipients: Vec<String>,
+
+    /// dumps only the given physical range, e.g. 0x100000-0x5894bfff (inclusive end, like in /proc/iomem). Can be set multiple times.
+    #[clap(long="range", value_parser=parse_range)]
+    ranges: Vec<Range<u64>>,
+
+    /// excludes the given physical range, e.g. 0x100000-0x1fffff (inclusive end). Can be set multiple times.
+    #[clap(long="exclude-range", value_parser=parse_range)]
+    exclude_ranges: Vec<Range<u64>>,
+
+    /// sets the iomem region type to dump, e.g. "ACPI Tables" - default is System RAM. Can be set multiple times.
+    #[clap(long="iomem-type")]
+    iomem_types: Vec<String>,
+
+    /// reads the memory with the given number of worker threads (each pinned to its own CPU). The output is identical to a sequential dump.
+    #[clap(long="threads", conflicts_with="pid")]
+    threads: Option<usize>,
+
+    /// sets how often unreadable pages are re-read (page by page), before they are zero filled (or skipped) - default is 1.
+    #[clap(long="read-retries", default_value="1")]
+    read_retries: u32,
+
+    /// dumps the memory of the given process (as ELF core file) instead of the physical memory.
+    #[clap(long="pid")]
+    pid: Option<i32>,
+
+    /// sets the output-format.
+    #[clap(short='f', long="output-format", global=true, value_enum, default_value="lime")]
+    output_format: OutputFormat,
+
+    /// sets the handling of unreadable zones: zero fills them, split omits them and starts a new LiME record (lime only).
+    #[clap(short='g', long="gap-mode", global=true, value_enum, default_value="zero")]
+    gap_mode: GapMode,
+
+    /// hashes the image (and the written output, if compression or encryption is used), e.g. --hash sha256,md5
+    #[clap(long="hash", global=true, value_enum, value_delimiter=',')]
+    hash: Vec<HashAlgorithm>,
+
+    /// writes the calculated hashes to the given file.
+    #[clap(long="hash-file", global=true, requires="hash")]
+    hash_file: Option<PathBuf>,
+
+    /// writes a JSON acquisition report (ranges, per-range hashes, unreadable zones, system information) to the given file.
+    #[clap(short='m', long="metadata", global=true)]
+    metadata: Option<PathBuf>,
+
+    /// adds a progress bar
+    #[clap(short='p', long="progress-bar", global=true)]
+    progress_bar: bool
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// receives a memory dump over the network (see --network) and writes it to disk.
+    Listen(ListenArgs),
+    /// decrypts an encrypted image (see --recipient).
+    Decrypt(DecryptArgs),
+    /// verifies an image (e.g. against its metadata) and fails on any discrepancy.
+    Verify(VerifyArgs),
+    /// converts an image into another format (see --output-format), e.g. a compressed lime image into an elf image.
+    Convert(ConvertArgs),
+}
+
+#[derive(Args)]
+struct DecryptArgs {
+    /// sets the age identity file, which contains the private key(s).
+    #[clap(short='i', long="identity")]
+    identity: PathBuf,
+
+    /// sets the target file (where the decrypted image will be written to).
+    #[clap(short='o', long="outputfile")]
+    output: PathBuf,
+
+    /// sets the encrypted image.
+    input: PathBuf,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// sets the acquisition metadata of the image (see --metadata), which the image is compared with.
+    #[clap(long="sidecar")]
+    sidecar: Option<PathBuf>,
+
+    /// sets a copy of /proc/iomem of the acquired system, which the covered ranges are compared with.
+    #[clap(long="iomem")]
+    iomem: Option<PathBuf>,
+
+    /// sets the iomem region type, which has been dumped - default is System RAM. Can be set multiple times.
+    #[clap(long="iomem-type", requires="iomem", default_value=IOMEM_TYPE_SYSTEM_RAM)]
+    iomem_types: Vec<String>,
+
+    /// sets the image (raw, padded-raw, lime, avml or elf - optionally compressed with zstd, lz4, gzip or xz).
+    input: PathBuf,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    /// sets the target file (where the converted image will be written to).
+    #[clap(short='o', long="outputfile")]
+    output: PathBuf,
+
+    /// sets the acquisition metadata of the image (see --metadata), which is taken over to the metadata of the converted image (necessary for raw images).
+    #[clap(long="sidecar")]
+    sidecar: Option<PathBuf>,
+
+    /// sets the image (raw, padded-raw, lime, avml or elf - optionally compressed with zstd, lz4, gzip or xz).
+    input: PathBuf,
+}
+
+#[derive(Args)]
+struct ListenArgs {
+    /// sets the address to listen on, e.g. 0.0.0.0:4444
+    #[clap(short='b', long="bind")]
+    bind: String,
+
+    /// sets the target file (where the received memory will be written to).
+    #[clap(short='o', long="outputfile")]
+    output: PathBuf,
+
+    /// sets the server certificate (PEM) and enables TLS.
+    #[clap(long="tls-cert", requires="tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// sets the private key (PEM) of the server certificate.
+    #[clap(long="tls-key", requires="tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// sets the CA certificate(s) (PEM), which are used to authenticate the sending side.
+    #[clap(long="tls-client-ca", requires="tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// pins the SHA-256 fingerprint of the sending side's certificate (needs --tls-client-ca).
+    #[clap(long="tls-client-pin", requires="tls_client_ca")]
+    tls_client_pin: Option<String>,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+    Gzip,
+    Xz,
+}
+
+#[derive(ValueEnum, Clone)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum OutputFormat {
+    Raw,
+    /// raw, but each range is written at the offset of its physical address (the holes are zero filled, sparse in uncompressed output files).
+    PaddedRaw,
+    Lime,
+    Elf,
+    /// LiME version 2 records with snappy compressed payloads (as written by Microsoft AVML).
+    Avml,
+}
+
+#[derive(ValueEnum, Clone)]
+pub enum GapMode {
+    Zero,
+    Split,
+}
+
+#[unsafe(no_mangle)]
+#[inline(never)]
+pub extern "C" fn _read_kernel_memory(_src_address: u64, _dump_size: usize) {}
+
+#[unsafe(no_mangle)]
+#[inline(never)]
+fn read_kernel_memory(offset: u64, dump_size: usize) {
+    let func: extern "C" fn(u64, usize) = _read_kernel_memory;
+    // unsafe block is necessary to ensure the compiler will not optimize this away.
+    unsafe {
+        std::ptr::read_volatile(&func);
+        func(offset, dump_size);
+    }
+}
+
+#[unsafe(no_mangle)]
+#[inline(never)]
+pub extern "C" fn _read_kernel_memory_slot(_src_address: u64, _dump_size: usize, _slot: u32) {}
+
+#[unsafe(no_mangle)]
+#[inline(never)]
+fn read_kernel_memory_slot(offset: u64, dump_size: usize, slot: u32) {
+    let func: extern "C" fn(u64, usize, u32) = _read_kernel_memory_slot;
+    // unsafe block is necessary to ensure the compiler will not optimize this away.
+    unsafe {
+        std::ptr::read_volatile(&func);
+        func(offset, dump_size, slot);
+    }
+}
+
+impl Default for Cli {
+    /// the defaults of the command line (without an output).
+    fn default() -> Self {
+        Self {
+            command: None,
+            output: None,
+            stdout: false,
+            split_size: None,
+            resume: false,
+            network: None,
+            tls: false,
+            tls_ca: None,
+            tls_pin: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_server_name: None,
+            log_level: LogLevel::Info,
+            compression: Compression::None,
+            compress_level: None,
+            compress_threads: None,
+            zstd_long: false,
+            zstd_window_log: None,
+            recipients: Vec::new(),
+            ranges: Vec::new(),
+            exclude_ranges: Vec::new(),
+            iomem_types: Vec::new(),
+            threads: None,
+            read_retries: 1,
+            pid: None,
+            output_format: OutputFormat::Lime,
+            gap_mode: GapMode::Zero,
+            hash: Vec::new(),
+            hash_file: None,
+            metadata: None,
+            progress_bar: false,
+        }
+    }
+}
+
+/// Runs emd with the given command line. Has to be called inside of a tokio runtime (necessary for aya_log and the signal handler).
+pub fn run(args: Cli) -> anyhow::Result<()> {
+    // setup the progress bar (only neccessary for the progress bar option is set)
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+
+    let log_level = match args.log_level {
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    };
+    let logger = env_logger::builder()
+        .format_timestamp_nanos()
+        .filter_level(log_level)
+        .build();
+
+    LogWrapper::new(multi.clone(), logger)
+    .try_init()?;
+
+    match &args.command {
+        Some(Command::Listen(listen_args)) => return listen(listen_args),
+        Some(Command::Decrypt(decrypt_args)) => return decrypt(decrypt_args),
+        Some(Command::Verify(verify_args)) => return verify(&args, verify_args),
+        Some(Command::Convert(convert_args)) => return convert(&args, convert_args),
+        None => (),
+    }
+
+    let pid = std::process::id();
+    info!("Using PID: {pid}");
+
+    let package_version = env!("CARGO_PKG_VERSION");
+    info!("Initializing {TOOL_NAME} {package_version}.");
+
+    // checked before the output is opened, so e.g. an existing output file is not truncated without the capabilities.
+    check_capabilities()?;
+    let output = select_output(&args)?;
+    let progress_bar = setup_progress_bar(&args, &multi);
+    let cancellation = Cancellation::new();
+    install_signal_handler(cancellation.clone())?;
+    let mut dumper = MemoryDumper::from_args(args).cancellation(cancellation.clone());
+    if let Some(pb) = progress_bar {
+        dumper = dumper.progress(move |done, total| {
+            pb.set_length(total);
+            pb.set_position(done);
+        });
+    }
+    dumper.dump(output)?;
+    if cancellation.is_cancelled() {
+        std::process::exit(EXIT_CODE_INTERRUPTED);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the fields of the command line (all of them, so a new field has to be added here as well).
+    fn fields(cli: &Cli) -> Vec<String> {
+        let Cli {
+            command, output, stdout, split_size, resume, network, tls, tls_ca, tls_pin, tls_cert, tls_key,
+            tls_server_name, log_level, compression, compress_level, compress_threads, zstd_long, zstd_window_log,
+            recipients, ranges, exclude_ranges, iomem_types, threads, read_retries, pid, output_format, gap_mode,
+            hash, hash_file, metadata, progress_bar,
+        } = cli;
+        vec![
+            format!("{}", command.is_some()), format!("{output:?}"), format!("{stdout}"), format!("{split_size:?}"),
+            format!("{resume}"), format!("{network:?}"), format!("{tls}"), format!("{tls_ca:?}"), format!("{tls_pin:?}"),
+            format!("{tls_cert:?}"), format!("{tls_key:?}"), format!("{tls_server_name:?}"), value_name(log_level),
+            value_name(compression), format!("{compress_level:?}"), format!("{compress_threads:?}"), format!("{zstd_long}"),
+            format!("{zstd_window_log:?}"), format!("{recipients:?}"), format!("{ranges:?}"), format!("{exclude_ranges:?}"),
+            format!("{iomem_types:?}"), format!("{threads:?}"), format!("{read_retries}"), format!("{pid:?}"),
+            value_name(output_format), value_name(gap_mode), format!("{hash:?}"), format!("{hash_file:?}"),
+            format!("{metadata:?}"), format!("{progress_bar}"),
+        ]
+    }
+
+    #[test]
+    fn default_matches_the_command_line_defaults() {
+        let parsed = Cli::try_parse_from([TOOL_NAME, "--outputfile", "image.lime"]).unwrap();
+        let default = Cli { output: Some(PathBuf::from("image.lime")), ..Cli::default() };
+        assert_eq!(fields(&parsed), fields(&default));
+    }
+}
@@ -3,126 +3,454 @@ use crate::traits::HumanReadable;
 // - parent
 use super::*;
 
-pub fn dump_physical_memory(
-    args: &Cli, 
-    buffer_queue: &mut Queue<&mut MapData, [u8; BUFFER_SIZE]>,
-    multi: &MultiProgress,
+/// Reads the physical memory: sequentially with the transport or with the worker threads of --threads,
+/// until the acquisition is cancelled.
+pub(crate) struct MemoryReader<'a> {
+    pub(crate) transport: &'a mut Transport,
+    pub(crate) parallel: Option<&'a ParallelReader>,
+    pub(crate) cancellation: &'a Cancellation,
+}
+
+/// Reads the zones of the memory, which are dumped.
+pub(crate) trait ZoneReader {
+    /// Reads the given zone in chunks of MAX_QUEUE_SIZE bytes and passes the elements in order
+    /// (with the offset of their chunk) to the given function.
+    /// Returns the end of the part of the zone, which has been read (before the end of the zone, if cancelled).
+    fn read_zone<F>(&mut self, zone: &Range<u64>, mapping_offset: u64, f: F) -> anyhow::Result<u64>
+    where
+        F: FnMut(u64, Element<'_>) -> anyhow::Result<()>;
+}
+
+impl ZoneReader for MemoryReader<'_> {
+    fn read_zone<F>(&mut self, zone: &Range<u64>, mapping_offset: u64, mut f: F) -> anyhow::Result<u64>
+    where
+        F: FnMut(u64, Element<'_>) -> anyhow::Result<()>,
+    {
+        if let Some(parallel) = self.parallel {
+            return parallel.read_zone(zone, mapping_offset, self.cancellation, f);
+        }
+        for offset in zone.clone().step_by(MAX_QUEUE_SIZE) {
+            if self.cancellation.is_cancelled() {
+                return Ok(offset);
+            }
+            debug!("Dumping 0x{offset:x}");
+            let dump_size = ((zone.end - offset) as usize).min(MAX_QUEUE_SIZE);
+            self.transport.read(mapping_offset+offset, dump_size, |element| f(offset, element))?;
+        }
+        Ok(zone.end)
+    }
+}
+
+pub(crate) fn dump_physical_memory(
+    args: &Cli,
+    reader: &mut MemoryReader,
+    output: Box<dyn OutputSink>,
+    format: Box<dyn ImageFormat>,
+    progress: &mut Progress,
 ) -> anyhow::Result<()> {
     info!("Extracting memory ranges.");
-    let system_ram_ranges = extract_mem_range(SEPARATOR_SYSTEM_RAM)?;
+    let memory_ranges = select_memory_ranges(args)?;
     info!("Calculating page offset base.");
-    let page_offset_base = get_page_offset_base(buffer_queue)?;
-    dump_mem(args, buffer_queue, system_ram_ranges, page_offset_base, multi)
+    let page_offset_base = get_page_offset_base(reader.transport)?;
+    dump_mem(args, reader, memory_ranges, page_offset_base, output, format, progress)
+}
+
+pub(crate) fn setup_progress_bar(args: &Cli, multi: &MultiProgress) -> Option<ProgressBar> {
+    if args.progress_bar {
+        let pb = multi.add(ProgressBar::new(0));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{decimal_bytes_per_sec}] [{wide_bar:.green/blue}] [{binary_bytes}/{binary_total_bytes}] [{percent}%] ({eta})")
+        .unwrap()
+        .progress_chars("=>-"));
+        Some(pb)
+    } else {
+        None
+    }
 }
 
-fn select_output(args: &Cli) -> anyhow::Result<Box<dyn Write>> {
+pub(crate) fn select_output(args: &Cli) -> anyhow::Result<Box<dyn OutputSink>> {
     if args.stdout {
         Ok(Box::new(stdout()))
-    } else {
-        let file = File::create(&args.output.as_ref().unwrap())?;
+    } else if let Some(address) = &args.network {
+        connect(args, address)
+    } else if let Some(split_size) = args.split_size {
+        Ok(Box::new(SplitWriter::new(args.output.as_ref().unwrap(), split_size, hash_algorithms_or_default(args))))
+    } else if args.resume {
+        // the output is truncated to the resume position afterwards (see Resume::new), the data is appended behind it.
+        let file = OpenOptions::new().append(true).open(args.output.as_ref().unwrap())?;
         Ok(Box::new(file))
+    } else {
+        let file = File::create(args.output.as_ref().unwrap())?;
+        Ok(file_output(args, file))
     }
 }
 
-fn prepare_writer(args: &Cli) -> anyhow::Result<Box<dyn Write>> {
-    let output = select_output(args)?;
-    match &args.compression {
-        Compression::None => {
-            Ok(output)
-        },
-        Compression::Zstd => {
-            let encoder = ZstdEncoder::new(output, 3)?;
-            Ok(Box::new(encoder.auto_finish()))
-        },
-        Compression::Lz4 => Ok(Box::new(Lz4Encoder::new(output)))
-    }    
+/// Returns the sink of the given output file: uncompressed and unencrypted padded raw images are written as sparse files.
+pub(crate) fn file_output(args: &Cli, file: File) -> Box<dyn OutputSink> {
+    if matches!(args.output_format, OutputFormat::PaddedRaw) && matches!(args.compression, Compression::None) && args.recipients.is_empty() {
+        Box::new(SparseFile::new(file))
+    } else {
+        Box::new(file)
+    }
+}
+
+/// Prepares the pipeline: the compression stage (if any) hashes and compresses the image,
+/// the writer stage encrypts (if necessary) and writes the output.
+pub(crate) fn prepare_writer(args: &Cli, hashes: &ImageHashes, mut output: Box<dyn OutputSink>) -> anyhow::Result<Pipeline> {
+    if matches!(args.gap_mode, GapMode::Split) && !matches!(args.output_format, OutputFormat::Lime) {
+        anyhow::bail!("The gap mode split is only supported by the lime output format.");
+    }
+    if matches!(args.output_format, OutputFormat::Avml) && !matches!(args.compression, Compression::None) {
+        anyhow::bail!("The avml output format is already compressed per range and cannot be combined with --compress.");
+    }
+    check_compression_args(args)?;
+    if let Some(output_hashes) = &hashes.output {
+        output = Box::new(HashWriter::new(output, output_hashes.clone()));
+    }
+    if !args.recipients.is_empty() {
+        output = Box::new(AgeSink::new(output, &args.recipients)?);
+    }
+    if let Compression::None = args.compression {
+        return Pipeline::new("writer", Box::new(HashWriter::new(output, hashes.image.clone())));
+    }
+    let mut pipeline = Pipeline::new("writer", output)?;
+    pipeline.prepend("compression", |output| {
+        Ok(Box::new(HashWriter::new(compression_writer(args, output)?, hashes.image.clone())))
+    })?;
+    Ok(pipeline)
 }
 
-fn dump_mem(
+pub(crate) fn dump_mem<R: ZoneReader>(
     args: &Cli,
-    buffer_queue: &mut Queue<&mut MapData, [u8; BUFFER_SIZE]>,
+    reader: &mut R,
     memory_range: Vec<Range<u64>>,
     mapping_offset: u64,
-    multi: &MultiProgress) -> anyhow::Result<()> {
+    output: Box<dyn OutputSink>,
+    mut format: Box<dyn ImageFormat>,
+    progress: &mut Progress) -> anyhow::Result<()> {
 
-    let mut output_file = BufWriter::new(prepare_writer(args)?);
+    let mut metadata = match args.metadata {
+        Some(_) => Some(AcquisitionMetadata::new(args, mapping_offset)?),
+        None => None,
+    };
+    let range_hash_algorithms = hash_algorithms_or_default(args);
 
-    let mut header = match args.output_format {
-        OutputFormat::Lime => Header::Lime(LimeHeader::default()),
-        OutputFormat::Raw => Header::None,
+    let hashes = ImageHashes::new(args);
+    let mut resume = match args.resume {
+        true => Some(Resume::new(args, &memory_range, &hashes, metadata.as_ref().map(|_| &range_hash_algorithms[..]))?),
+        false => None,
     };
+    if let (Some(metadata), Some(resume)) = (&mut metadata, &resume) {
+        metadata.resumed(resume);
+    }
+    let mut checkpoint = is_resumable(args).then(|| CheckpointWriter::new(args, &memory_range, resume.as_ref()));
+    let resumed_size = resume.as_ref().map_or(0, |resume| resume.output_size);
+    let mut output_file = prepare_writer(args, &hashes, output)?;
+
+    // the beginning of a resumed image is already written.
+    if resume.is_none() {
+        format.begin_image(&mut output_file, &ImageInfo { ranges: &memory_range, page_offset_base: mapping_offset })?;
+    }
 
     // calculate memory size for progress bar
-    let memory_size = memory_size()?;
+    let memory_size = memory_size(&memory_range);
     info!("Total size to dump: {}", memory_size.bytes_as_hrb());
 
-    let progress_bar = if args.progress_bar {
-        let pb = multi.add(ProgressBar::new(memory_size));
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{decimal_bytes_per_sec}] [{wide_bar:.green/blue}] [{binary_bytes}/{binary_total_bytes}] [{percent}%] ({eta})")
-        .unwrap()
-        .progress_chars("=>-"));
-        Some(pb)
-    } else {
-        None
-    };
-
-    for range in memory_range {
-        let range_len = range.end - range.start;
-        let range_end = range.end;
-        let range_start = range.start;
-        info!("Dumping 0x{range_start:x} - 0x{range_end:x}");
-        for offset in range.step_by(MAX_QUEUE_SIZE) {
-
-            debug!("Dumping 0x{offset:x}");
-            let remaining = (range_len - (offset - range_start)) as usize;
-            let dump_size = if remaining < MAX_QUEUE_SIZE {
-                remaining
-            } else {
-                MAX_QUEUE_SIZE
-            };
-            
-            read_kernel_memory(mapping_offset+offset, dump_size);
-
-            let queue_elements = calc_queue_elements(dump_size);
-            let mut unreadable_offsets = Vec::new();
-            let mut optional_header_end_address = offset; // only used by e.g. LimeHeader
-            for i in 0..queue_elements {
-                let queue_element_size = if i == queue_elements && dump_size % BUFFER_SIZE != 0 {
-                    dump_size % BUFFER_SIZE
-                } else {
-                    BUFFER_SIZE
-                };
-                let buffer = match buffer_queue.pop(0) {
-                    Ok(value) => {
-                        optional_header_end_address += queue_element_size as u64;
-                        value
-                    },
-                    Err(_) => {
-                        unreadable_offsets.push((offset, i));
-                        optional_header_end_address += queue_element_size as u64;
-                        [0u8; BUFFER_SIZE]
-                    }
-                };
+    progress.start(memory_size);
+    let mut interrupted_at = None;
+    // the end of the data, which the format has announced before the acquisition has been interrupted.
+    let mut announced_end = None;
+    if let Some(resume) = &resume {
+        progress.inc(resume.data_size);
+    }
 
-                if let Header::Lime(header) = &mut header {
-                    header.start_address = offset;
-                    header.end_address = optional_header_end_address;
-                    output_file.write_all(&header.as_bytes())?;
+    for (index, range) in memory_range.into_iter().enumerate() {
+        let mut range_record = metadata.as_ref().map(|_| RangeRecord::new(&range, &range_hash_algorithms));
+        if let Some(announced_end) = announced_end {
+            if range.start >= announced_end {
+                break;
+            }
+            // e.g. the ranges of an ELF core file, which are announced by the program headers.
+            format.begin_range(&mut output_file, &range)?;
+            zero_fill(format.as_mut(), &mut output_file, range.start..range.end.min(announced_end), range_record.as_mut())?;
+            if let (Some(metadata), Some(range_record)) = (&mut metadata, range_record) {
+                metadata.push_range(range_record);
+            }
+            continue;
+        }
+        let mut remaining = range.clone();
+        let mut header_written = false;
+        if let Some(resume) = &mut resume {
+            if index < resume.range_index {
+                if let (Some(metadata), Some(range_record)) = (&mut metadata, resume.take_record(index)) {
+                    metadata.push_range(range_record);
                 }
-                output_file.write_all(&buffer[..queue_element_size])?;
-                if let Some(ref pb) = progress_bar {
-                    pb.inc(queue_element_size as u64)
-                };
+                continue;
             }
-            for (offset, i) in unreadable_offsets {
-                // only necessary to print in warning.
-                let start_offset = offset + (BUFFER_SIZE * i) as u64;
-                let end_offset = offset + (BUFFER_SIZE * (queue_elements-1)) as u64;
-                warn!("Could not read 0x{start_offset:x} - 0x{end_offset:x}. Writing zeros for appropriate zone.");
+            if index == resume.range_index {
+                remaining.start = resume.address;
+                header_written = resume.header_written;
+                if let Some(resumed_record) = resume.take_record(index) {
+                    range_record = Some(resumed_record);
+                }
             }
         }
+        info!("Dumping 0x{:x} - 0x{:x}", remaining.start, remaining.end);
+        let end = match args.gap_mode {
+            GapMode::Zero => {
+                if !header_written {
+                    // otherwise the beginning of a resumed range (e.g. the LiME header) is already part of the image.
+                    format.begin_range(&mut output_file, &remaining)?;
+                }
+                reader.read_zone(&remaining, mapping_offset, |offset, element| {
+                    let element_address = offset + element.offset as u64;
+                    if !element.is_readable() {
+                        for (zone, _) in element.zones().into_iter().filter(|(_, readable)| !readable) {
+                            let zone = element_address + zone.start as u64..element_address + zone.end as u64;
+                            warn!("Could not read 0x{:x} - 0x{:x}. Writing zeros for appropriate zone.", zone.start, zone.end);
+                            if let Some(checkpoint) = &mut checkpoint {
+                                checkpoint.zero_filled(zone.clone());
+                            }
+                            format.record_gap(&mut output_file, &zone)?;
+                            if let Some(range_record) = &mut range_record {
+                                range_record.zero_filled(zone);
+                            }
+                        }
+                    }
+                    let data = element.data_or_zeros();
+                    format.write_data(&mut output_file, element_address, data)?;
+                    if let Some(range_record) = &mut range_record {
+                        range_record.update(data);
+                    }
+                    progress.inc(element.size as u64);
+                    if let Some(checkpoint) = &mut checkpoint {
+                        checkpoint.update(resumed_size + output_file.bytes_written(), false)?;
+                    }
+                    Ok(())
+                })?
+            },
+            GapMode::Split => {
+                let records = ZoneRecords::new(format.as_mut(), &mut output_file, range.start);
+                dump_zones(reader, &range, &remaining, mapping_offset, records, range_record.as_mut(), progress)?
+            },
+        };
+        if let Some(checkpoint) = &mut checkpoint {
+            checkpoint.update(resumed_size + output_file.bytes_written(), true)?;
+        }
+        if end < remaining.end {
+            interrupted_at = Some(end);
+            // the zeros are written behind the checkpoint, so a resumed acquisition overwrites them.
+            let end_of_data = format.announced_end(&range, end);
+            zero_fill(format.as_mut(), &mut output_file, end..range.end.min(end_of_data), range_record.as_mut())?;
+            announced_end = Some(end_of_data);
+        }
+        if let (Some(metadata), Some(range_record)) = (&mut metadata, range_record) {
+            metadata.push_range(range_record);
+        }
+    }
+    // the image is finished regularly, even if the acquisition has been interrupted.
+    format.finish(&mut output_file)?;
+    output_file.finish()?; // finishes all stages (and the compression, if any), before the hashes are finalized.
+    if let Some(address) = interrupted_at {
+        warn!("The acquisition has been interrupted at 0x{address:x}. The image is incomplete (the announced data is zero filled).");
+        if let Some(metadata) = &mut metadata {
+            metadata.interrupted(address);
+        }
+    } else if let Some(checkpoint) = checkpoint {
+        checkpoint.remove()?;
+    }
+    let digests = hashes.finalize();
+    if !args.hash.is_empty() {
+        digests.report(args)?;
+    }
+    if let (Some(metadata), Some(path)) = (metadata, &args.metadata) {
+        metadata.finish(&digests, path)?;
     }
-    output_file.flush()?; // flush the buffer
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Writes zeros for the given zone, e.g. for the data, which has been announced behind the interruption of the acquisition.
+/// The zone is recorded as zero filled.
+pub(crate) fn zero_fill(format: &mut dyn ImageFormat, output: &mut dyn OutputSink, zone: Range<u64>, mut range_record: Option<&mut RangeRecord>) -> anyhow::Result<()> {
+    if let Some(range_record) = range_record.as_mut().filter(|_| !zone.is_empty()) {
+        range_record.zero_filled(zone.clone());
+    }
+    let mut address = zone.start;
+    while address < zone.end {
+        let zeros = &ZERO_BUFFER[..(zone.end - address).min(ZERO_BUFFER.len() as u64) as usize];
+        format.write_data(output, address, zeros)?;
+        if let Some(range_record) = &mut range_record {
+            range_record.update(zeros);
+        }
+        address += zeros.len() as u64;
+    }
+    Ok(())
+}
+
+/// Dumps the remaining part of the given range in gap mode split (in a single pass): the readable zones are written as
+/// records, the unreadable zones are skipped. Returns the end of the part, which has been read (see read_zone).
+fn dump_zones<R: ZoneReader>(
+    reader: &mut R,
+    range: &Range<u64>,
+    remaining: &Range<u64>,
+    mapping_offset: u64,
+    mut records: ZoneRecords,
+    mut range_record: Option<&mut RangeRecord>,
+    progress: &mut Progress) -> anyhow::Result<u64> {
+
+    let mut skipped_start = None;
+    let end = reader.read_zone(remaining, mapping_offset, |offset, element| {
+        for (zone, readable) in element.zones() {
+            let zone_address = offset + (element.offset + zone.start) as u64;
+            match (readable, element.data) {
+                (true, Some(data)) => {
+                    if let Some(skipped_start) = skipped_start.take() {
+                        skipped(skipped_start..zone_address, &mut range_record);
+                    }
+                    let data = &data[zone];
+                    if let Some(range_record) = &mut range_record {
+                        range_record.update(data);
+                    }
+                    records.push(zone_address, data)?;
+                },
+                _ => {
+                    records.flush()?;
+                    skipped_start.get_or_insert(zone_address);
+                },
+            }
+        }
+        progress.inc(element.size as u64);
+        Ok(())
+    })?;
+    records.flush()?;
+    if let Some(skipped_start) = skipped_start {
+        skipped(skipped_start..end, &mut range_record);
+    }
+    if end == range.end && records.gap_start < range.end {
+        records.format.record_gap(records.output, &(records.gap_start..range.end))?;
+    }
+    Ok(end)
+}
+
+/// Buffers the readable zone, which is read currently, until its end is known (e.g. the LiME header contains the end).
+struct ZoneRecords<'a> {
+    format: &'a mut dyn ImageFormat,
+    output: &'a mut Pipeline,
+    /// the address of the buffered data.
+    start: u64,
+    data: Vec<u8>,
+    /// the end of the last record.
+    gap_start: u64,
+}
+
+impl<'a> ZoneRecords<'a> {
+    fn new(format: &'a mut dyn ImageFormat, output: &'a mut Pipeline, gap_start: u64) -> Self {
+        Self { format, output, start: gap_start, data: Vec::new(), gap_start }
+    }
+
+    fn push(&mut self, address: u64, data: &[u8]) -> anyhow::Result<()> {
+        if self.data.is_empty() {
+            self.start = address;
+        }
+        self.data.extend_from_slice(data);
+        if self.data.len() >= MAX_SPLIT_RECORD_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered data as record.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let zone = self.start..self.start + self.data.len() as u64;
+        // unreadable zones, which are omitted.
+        if zone.start > self.gap_start {
+            self.format.record_gap(self.output, &(self.gap_start..zone.start))?;
+        }
+        self.format.begin_range(self.output, &zone)?;
+        self.format.write_data(self.output, zone.start, &self.data)?;
+        self.gap_start = zone.end;
+        self.data.clear();
+        Ok(())
+    }
+}
+
+fn skipped(zone: Range<u64>, range_record: &mut Option<&mut RangeRecord>) {
+    warn!("Could not read 0x{:x} - 0x{:x}. Skipping appropriate zone.", zone.start, zone.end);
+    if let Some(range_record) = range_record {
+        range_record.skipped(zone);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const RANGES: [Range<u64>; 3] = [
+        0..2 * MAX_QUEUE_SIZE as u64,
+        3 * MAX_QUEUE_SIZE as u64..6 * MAX_QUEUE_SIZE as u64,
+        8 * MAX_QUEUE_SIZE as u64..9 * MAX_QUEUE_SIZE as u64,
+    ];
+    const INTERRUPTED_AT: u64 = 4 * MAX_QUEUE_SIZE as u64;
+
+    /// Dumps RANGES with dump_mem, interrupted at INTERRUPTED_AT.
+    fn interrupted_image(output_format: &OutputFormat) -> Vec<u8> {
+        let args = Cli { output_format: output_format.clone(), ..Cli::default() };
+        let sink = SharedSink::default();
+        let mut reader = TestReader { cancel_at: Some(INTERRUPTED_AT), ..TestReader::default() };
+        dump_mem(&args, &mut reader, RANGES.to_vec(), TEST_PAGE_OFFSET_BASE, Box::new(sink.clone()),
+            builtin_format(output_format), &mut Progress::new(None)).unwrap();
+        assert!(sink.is_finished());
+        sink.data()
+    }
+
+    /// The data of an image, which has been interrupted at INTERRUPTED_AT and zero filled up to the given end.
+    fn interrupted_data(zero_filled_end: u64) -> Vec<u8> {
+        let mut expected: Vec<u8> = RANGES.iter().flat_map(|range| pattern(range.start..range.end.min(INTERRUPTED_AT))).collect();
+        let zeros: u64 = RANGES.iter().map(|range| range.end.min(zero_filled_end).saturating_sub(range.start.max(INTERRUPTED_AT))).sum();
+        expected.resize(expected.len() + zeros as usize, 0);
+        expected
+    }
+
+    #[test]
+    fn interrupted_lime_image_is_valid() {
+        let (summary, records) = read(&interrupted_image(&OutputFormat::Lime), &OutputFormat::Lime);
+        assert!(summary.end.is_complete(), "{}", summary.end);
+        // the record of the interrupted range is complete, the ranges behind it are not written.
+        assert_eq!(records.ranges, RANGES[..2]);
+        assert_eq!(records.data, interrupted_data(RANGES[1].end));
+    }
+
+    #[test]
+    fn interrupted_elf_image_is_valid() {
+        let (summary, records) = read(&interrupted_image(&OutputFormat::Elf), &OutputFormat::Elf);
+        assert!(summary.end.is_complete(), "{}", summary.end);
+        assert_eq!(summary.page_offset_base, Some(TEST_PAGE_OFFSET_BASE));
+        // the program headers announce all ranges, so all of them are zero filled.
+        assert_eq!(records.ranges, RANGES);
+        assert_eq!(records.data, interrupted_data(RANGES[2].end));
+    }
+
+    #[test]
+    fn interrupted_raw_image_is_not_filled() {
+        assert_eq!(interrupted_image(&OutputFormat::Raw), interrupted_data(INTERRUPTED_AT));
+    }
+
+    #[test]
+    fn unreadable_pages_are_zero_filled() {
+        let unreadable = 0x5000..0x7000;
+        let args = Cli { output_format: OutputFormat::Lime, ..Cli::default() };
+        let sink = SharedSink::default();
+        let mut reader = TestReader { unreadable: vec![unreadable.clone()], ..TestReader::default() };
+        dump_mem(&args, &mut reader, RANGES[..1].to_vec(), 0, Box::new(sink.clone()),
+            builtin_format(&OutputFormat::Lime), &mut Progress::new(None)).unwrap();
+        let (summary, records) = read(&sink.data(), &OutputFormat::Lime);
+        assert!(summary.end.is_complete(), "{}", summary.end);
+        let mut expected = pattern(RANGES[0].clone());
+        expected[unreadable.start as usize..unreadable.end as usize].fill(0);
+        assert_eq!(records.data, expected);
+    }
+}
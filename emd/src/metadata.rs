@@ -0,0 +1,224 @@
+// - STD
+use std::{collections::BTreeMap, path::Path, time::SystemTime};
+
+// - parent
+use super::*;
+
+// - External
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The acquisition report, which will be written as JSON sidecar (see --metadata).
+#[derive(Serialize)]
+pub(crate) struct AcquisitionMetadata {
+    tool: &'static str,
+    version: &'static str,
+    command_line: Vec<String>,
+    hostname: String,
+    os_release: String,
+    #[serde(serialize_with = "serialize_hex")]
+    page_offset_base: u64,
+    output_format: String,
+    compression: CompressionParameters,
+    recipients: Vec<String>,
+    read_retries: u32,
+    start_time: String,
+    end_time: Option<String>,
+    status: AcquisitionStatus,
+    /// the address, where an interrupted acquisition has been stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interrupted_at: Option<String>,
+    /// bytes of the image, which were written before the acquisition was resumed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resumed_bytes: Option<u64>,
+    ranges: Vec<RangeMetadata>,
+    image_hashes: BTreeMap<String, String>,
+    output_hashes: BTreeMap<String, String>,
+}
+
+impl AcquisitionMetadata {
+    /// Collects the system information. Should be called right before the acquisition starts.
+    pub(crate) fn new(args: &Cli, page_offset_base: u64) -> anyhow::Result<Self> {
+        Ok(Self {
+            tool: TOOL_NAME,
+            version: env!("CARGO_PKG_VERSION"),
+            command_line: std::env::args().collect(),
+            hostname: get_hostname()?,
+            os_release: get_os_release()?,
+            page_offset_base,
+            output_format: value_name(&args.output_format),
+            compression: CompressionParameters::new(args),
+            recipients: args.recipients.clone(),
+            read_retries: args.read_retries,
+            start_time: now(),
+            end_time: None,
+            status: AcquisitionStatus::Completed,
+            interrupted_at: None,
+            resumed_bytes: None,
+            ranges: Vec::new(),
+            image_hashes: BTreeMap::new(),
+            output_hashes: BTreeMap::new(),
+        })
+    }
+
+    /// Takes the start time of the interrupted acquisition.
+    pub(crate) fn resumed(&mut self, resume: &Resume) {
+        self.start_time = resume.start_time.clone();
+        self.resumed_bytes = Some(resume.output_size);
+    }
+
+    /// Marks the acquisition as interrupted (at the given address).
+    pub(crate) fn interrupted(&mut self, address: u64) {
+        self.status = AcquisitionStatus::Interrupted;
+        self.interrupted_at = Some(format!("0x{address:x}"));
+    }
+
+    pub(crate) fn push_range(&mut self, range: RangeRecord) {
+        self.ranges.push(range.finalize());
+    }
+
+    /// Sets the end time and the image hashes and writes the metadata to the given file.
+    pub(crate) fn finish(mut self, digests: &ImageDigests, path: &Path) -> anyhow::Result<()> {
+        self.end_time = Some(now());
+        self.image_hashes = digests_as_map(&digests.image);
+        self.output_hashes = digests_as_map(&digests.output);
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self)?;
+        Ok(())
+    }
+}
+
+/// The acquisition report of an existing image (see AcquisitionMetadata), as far as it is needed to verify or convert the image.
+#[derive(Deserialize)]
+pub(crate) struct ImageMetadata {
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub(crate) page_offset_base: u64,
+    pub(crate) output_format: String,
+    pub(crate) compression: CompressionParameters,
+    pub(crate) recipients: Vec<String>,
+    pub(crate) status: AcquisitionStatus,
+    pub(crate) interrupted_at: Option<String>,
+    pub(crate) ranges: Vec<RangeMetadata>,
+    pub(crate) image_hashes: BTreeMap<String, String>,
+    pub(crate) output_hashes: BTreeMap<String, String>,
+}
+
+impl ImageMetadata {
+    pub(crate) fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| anyhow::anyhow!("Could not parse the metadata {}: {e}", path.display()))
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AcquisitionStatus {
+    Completed,
+    Interrupted,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RangeMetadata {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub(crate) start: u64,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub(crate) end: u64,
+    pub(crate) bytes: u64,
+    pub(crate) hashes: BTreeMap<String, String>,
+    pub(crate) zero_filled: Vec<ZoneMetadata>,
+    pub(crate) skipped: Vec<ZoneMetadata>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ZoneMetadata {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub(crate) start: u64,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub(crate) end: u64,
+}
+
+impl ZoneMetadata {
+    pub(crate) fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+}
+
+impl From<&Range<u64>> for ZoneMetadata {
+    fn from(zone: &Range<u64>) -> Self {
+        Self {
+            start: zone.start,
+            end: zone.end,
+        }
+    }
+}
+
+/// Collects the hashes and the unreadable zones of a single physical range while it is dumped.
+pub(crate) struct RangeRecord {
+    range: Range<u64>,
+    bytes: u64,
+    hashes: StreamHashes,
+    zero_filled: Vec<Range<u64>>,
+    skipped: Vec<Range<u64>>,
+}
+
+impl RangeRecord {
+    pub(crate) fn new(range: &Range<u64>, algorithms: &[HashAlgorithm]) -> Self {
+        Self {
+            range: range.clone(),
+            bytes: 0,
+            hashes: StreamHashes::new(algorithms),
+            zero_filled: Vec::new(),
+            skipped: Vec::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        self.hashes.update(data);
+    }
+
+    pub(crate) fn zero_filled(&mut self, zone: Range<u64>) {
+        // adjacent zones (e.g. of consecutive elements) are merged.
+        match self.zero_filled.last_mut() {
+            Some(last) if last.end == zone.start => last.end = zone.end,
+            _ => self.zero_filled.push(zone),
+        }
+    }
+
+    pub(crate) fn skipped(&mut self, zone: Range<u64>) {
+        self.skipped.push(zone);
+    }
+
+    pub(crate) fn finalize(self) -> RangeMetadata {
+        RangeMetadata {
+            start: self.range.start,
+            end: self.range.end,
+            bytes: self.bytes,
+            hashes: digests_as_map(&self.hashes.finalize()),
+            zero_filled: self.zero_filled.iter().map(ZoneMetadata::from).collect(),
+            skipped: self.skipped.iter().map(ZoneMetadata::from).collect(),
+        }
+    }
+}
+
+pub(crate) fn digests_as_map(digests: &[(HashAlgorithm, String)]) -> BTreeMap<String, String> {
+    digests.iter().map(|(algorithm, digest)| (value_name(algorithm), digest.clone())).collect()
+}
+
+pub(crate) fn value_name<V: ValueEnum>(value: &V) -> String {
+    value.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default()
+}
+
+pub(crate) fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+fn serialize_hex<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{value:x}"))
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    let hex = value.strip_prefix("0x").unwrap_or(&value);
+    u64::from_str_radix(hex, 16).map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,189 @@
+// - STD
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+};
+
+// - parent
+use super::*;
+
+use crate::traits::HumanReadable;
+
+// - External
+use sha2::{Digest, Sha256};
+
+/// size of the buffer, which is used to receive the memory.
+const RECEIVE_BUFFER_SIZE: usize = 0x10000;
+
+/// Connects to the remote collector (see `emd listen`), which receives the dumped memory.
+pub(crate) fn connect(args: &Cli, address: &str) -> anyhow::Result<Box<dyn OutputSink>> {
+    info!("Connecting to {address}.");
+    let stream = TcpStream::connect(address)?;
+    info!("Connected to {}.", stream.peer_addr()?);
+    if args.tls {
+        Ok(Box::new(connect_tls(args, stream)?))
+    } else {
+        Ok(Box::new(TcpSink::new(stream)))
+    }
+}
+
+/// The sending side of a plain TCP connection. Appends a trailer with the length and the SHA-256 digest of the
+/// data when finished, so the collector can distinguish a complete transfer from a truncated one (TLS uses the
+/// close_notify alert instead).
+pub(crate) struct TcpSink {
+    stream: TcpStream,
+    hasher: Sha256,
+    length: u64,
+}
+
+impl TcpSink {
+    pub(crate) fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            hasher: Sha256::new(),
+            length: 0,
+        }
+    }
+}
+
+impl Write for TcpSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl OutputSink for TcpSink {
+    fn finish(&mut self) -> io::Result<()> {
+        self.stream.write_all(NETWORK_TRAILER_MAGIC)?;
+        self.stream.write_all(&self.length.to_le_bytes())?;
+        self.stream.write_all(&self.hasher.clone().finalize())?;
+        self.stream.flush()
+    }
+}
+
+/// Waits for a single incoming acquisition and writes the received stream (as is) to the output file.
+pub(crate) fn listen(args: &ListenArgs) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&args.bind)?;
+    info!("Listening on {}.", listener.local_addr()?);
+    let (mut stream, peer) = listener.accept()?;
+    info!("Receiving memory from {peer}.");
+    let mut output_file = BufWriter::new(File::create(&args.output)?);
+    let received = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => io::copy(&mut accept_tls(args, stream, cert, key)?, &mut output_file)?,
+        _ => receive(&mut stream, &mut output_file)?,
+    };
+    output_file.flush()?;
+    info!("Received {} and wrote it to {}.", received.bytes_as_hrb(), args.output.display());
+    Ok(())
+}
+
+/// Copies the data of a plain TCP transfer to the output and checks the trailer (see TcpSink). Fails, if the
+/// stream ends without a valid trailer.
+fn receive<R: Read, W: Write>(stream: &mut R, output: &mut W) -> anyhow::Result<u64> {
+    let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+    // the last bytes of the stream are held back, until it is clear, that they are not part of the trailer.
+    let mut tail = Vec::with_capacity(RECEIVE_BUFFER_SIZE + NETWORK_TRAILER_SIZE);
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    loop {
+        let size = match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        tail.extend_from_slice(&buffer[..size]);
+        if tail.len() > NETWORK_TRAILER_SIZE {
+            let data = tail.len() - NETWORK_TRAILER_SIZE;
+            output.write_all(&tail[..data])?;
+            hasher.update(&tail[..data]);
+            received += data as u64;
+            tail.drain(..data);
+        }
+    }
+
+    if tail.len() < NETWORK_TRAILER_SIZE || &tail[..8] != NETWORK_TRAILER_MAGIC {
+        anyhow::bail!("The transfer is incomplete (no trailer received after {}).", received.bytes_as_hrb());
+    }
+    let length = u64::from_le_bytes(tail[8..16].try_into()?);
+    if length != received {
+        anyhow::bail!("The transfer is incomplete ({} received, the trailer announces {}).", received.bytes_as_hrb(), length.bytes_as_hrb());
+    }
+    if hasher.finalize().as_slice() != &tail[16..] {
+        anyhow::bail!("The SHA-256 digest of the received data does not match the trailer.");
+    }
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::thread;
+
+    /// Sends the data over a loopback connection (finished, if `finish` is set) and receives it.
+    fn transfer(data: Vec<u8>, finish: bool) -> (anyhow::Result<u64>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let mut sink = TcpSink::new(TcpStream::connect(address).unwrap());
+            sink.write_all(&data).unwrap();
+            if finish {
+                sink.finish().unwrap();
+            }
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut output = Vec::new();
+        let result = receive(&mut stream, &mut output);
+        sender.join().unwrap();
+        (result, output)
+    }
+
+    fn test_data() -> Vec<u8> {
+        pattern(0..3 * RECEIVE_BUFFER_SIZE as u64 + 17)
+    }
+
+    #[test]
+    fn loopback_transfer() {
+        let data = test_data();
+        let (result, output) = transfer(data.clone(), true);
+        assert_eq!(result.unwrap(), data.len() as u64);
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn empty_transfer() {
+        let (result, output) = transfer(Vec::new(), true);
+        assert_eq!(result.unwrap(), 0);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn truncated_transfer() {
+        let (result, _) = transfer(test_data(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn corrupted_trailer() {
+        let data = test_data();
+        let mut stream = data.clone();
+        stream.extend_from_slice(NETWORK_TRAILER_MAGIC);
+        stream.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        stream.extend_from_slice(&Sha256::digest(b"other data"));
+        assert!(receive(&mut stream.as_slice(), &mut Vec::new()).is_err());
+
+        let mut stream = data[..data.len() - 1].to_vec();
+        stream.extend_from_slice(NETWORK_TRAILER_MAGIC);
+        stream.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        stream.extend_from_slice(&Sha256::digest(&data));
+        assert!(receive(&mut stream.as_slice(), &mut Vec::new()).is_err());
+    }
+}
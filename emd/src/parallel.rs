@@ -0,0 +1,135 @@
+// - STD
+use std::{
+    sync::mpsc::{sync_channel, SyncSender},
+    thread,
+};
+
+// - parent
+use super::*;
+
+// - External
+use aya::maps::Array;
+
+/// chunks, which may be buffered per worker thread until the reassembly stage writes them.
+const CHUNKS_IN_FLIGHT: usize = 2;
+
+/// Reads the memory with several worker threads, each pinned to its own CPU and using its own slot of the
+/// slot buffers. The chunks are distributed round-robin to the workers and reassembled in order,
+/// so the output is identical to a sequential dump.
+pub(crate) struct ParallelReader {
+    buffers: Array<MapData, [u8; BUFFER_SIZE]>,
+    status: Array<MapData, ReadStatus>,
+    cpus: Vec<u32>,
+    /// how often unreadable pages are re-read.
+    retries: u32,
+}
+
+/// Returns the CPUs, the worker threads are pinned to (at most one worker per online CPU).
+pub(crate) fn worker_cpus(threads: usize) -> anyhow::Result<Vec<u32>> {
+    let online_cpus = aya::util::online_cpus().map_err(|(path, e)| anyhow::anyhow!("Could not read {path}: {e}"))?;
+    if threads == 0 {
+        anyhow::bail!("At least one thread is necessary.");
+    }
+    if threads > online_cpus.len() {
+        warn!("Only {} CPUs are online, using {} threads instead of {threads}.", online_cpus.len(), online_cpus.len());
+    }
+    Ok(online_cpus.into_iter().take(threads).collect())
+}
+
+impl ParallelReader {
+    pub(crate) fn new(ebpf: &mut Ebpf, cpus: Vec<u32>, retries: u32) -> anyhow::Result<Self> {
+        let buffers = Array::try_from(ebpf.take_map(MAP_BUFFER_SLOTS).unwrap())?;
+        let status = Array::try_from(ebpf.take_map(MAP_BUFFER_SLOTS_STATUS).unwrap())?;
+        Ok(Self { buffers, status, cpus, retries })
+    }
+
+    /// Reads the given zone and passes the elements in order (with the offset of their chunk) to the given function.
+    /// Returns the end of the part of the zone, which has been read (before the end of the zone, if cancelled).
+    pub(crate) fn read_zone<F>(&self, zone: &Range<u64>, mapping_offset: u64, cancellation: &Cancellation, mut f: F) -> anyhow::Result<u64>
+    where
+        F: FnMut(u64, Element<'_>) -> anyhow::Result<()>,
+    {
+        let chunks = (zone.end - zone.start).div_ceil(MAX_QUEUE_SIZE as u64);
+        let workers = self.cpus.len();
+        thread::scope(|scope| {
+            let mut receivers = Vec::with_capacity(workers);
+            for (slot, cpu) in self.cpus.iter().enumerate() {
+                let (sender, receiver) = sync_channel(CHUNKS_IN_FLIGHT);
+                receivers.push(receiver);
+                scope.spawn(move || self.worker(slot, *cpu, zone, mapping_offset, chunks, sender));
+            }
+            // the receivers are dropped on errors, which stops the workers.
+            for index in 0..chunks {
+                if cancellation.is_cancelled() {
+                    return Ok(zone.start + index * MAX_QUEUE_SIZE as u64);
+                }
+                let chunk = receivers[index as usize % workers].recv()??;
+                let offset = chunk.address - mapping_offset;
+                debug!("Dumping 0x{offset:x}");
+                chunk.elements(|element| f(offset, element))?;
+            }
+            Ok(zone.end)
+        })
+    }
+
+    fn worker(
+        &self,
+        slot: usize,
+        cpu: u32,
+        zone: &Range<u64>,
+        mapping_offset: u64,
+        chunks: u64,
+        sender: SyncSender<anyhow::Result<Chunk>>) {
+
+        if let Err(e) = pin_to_cpu(cpu) {
+            let _ = sender.send(Err(anyhow::anyhow!("Could not pin worker thread to CPU {cpu}: {e}")));
+            return;
+        }
+        for index in (slot as u64..chunks).step_by(self.cpus.len()) {
+            let offset = zone.start + index * MAX_QUEUE_SIZE as u64;
+            let dump_size = ((zone.end - offset) as usize).min(MAX_QUEUE_SIZE);
+            let chunk = self.read(slot as u32, mapping_offset + offset, dump_size);
+            let failed = chunk.is_err();
+            if sender.send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    }
+
+    /// Reads the chunk with the slot of the calling worker thread. Unreadable pages are re-read page by page.
+    fn read(&self, slot: u32, address: u64, dump_size: usize) -> anyhow::Result<Chunk> {
+        let mut chunk = self.read_chunk(slot, address, dump_size)?;
+        chunk.read_remainder(|address, size| self.read_chunk(slot, address, size))?;
+        chunk.retry_failed_pages(self.retries, |address, size| self.read_chunk(slot, address, size))?;
+        Ok(chunk)
+    }
+
+    fn read_chunk(&self, slot: u32, address: u64, dump_size: usize) -> anyhow::Result<Chunk> {
+        read_kernel_memory_slot(address, dump_size, slot);
+        let status = self.status.get(&slot, 0)?;
+        let elements_pushed = check_status(&status, address, dump_size)?;
+        let mut chunk = Chunk::new(address, dump_size);
+        for i in 0..calc_queue_elements(dump_size) {
+            if i < elements_pushed {
+                chunk.set_element(i, &self.buffers.get(&(slot * QUEUE_SIZE + i as u32), 0)?, &status);
+            } else {
+                chunk.set_element_failed(i);
+            }
+        }
+        chunk.set_request_end(&status);
+        Ok(chunk)
+    }
+}
+
+fn pin_to_cpu(cpu: u32) -> std::io::Result<()> {
+    // Safety: cpu_set_t is a plain bitmask, which is initialized by zeroing.
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu as usize, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
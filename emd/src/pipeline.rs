@@ -0,0 +1,348 @@
+// - STD
+use std::{
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, SyncSender, sync_channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// - parent
+use super::*;
+
+use crate::traits::HumanReadable;
+
+/// size of the blocks, which are passed between the stages.
+const PIPELINE_BLOCK_SIZE: usize = MAX_QUEUE_SIZE;
+/// blocks, which are queued in front of a stage, before the previous stage is blocked (backpressure).
+const PIPELINE_QUEUE_DEPTH: usize = 8;
+
+/// A message to a stage.
+enum Block {
+    Data(Vec<u8>),
+    /// the given number of zeros, which are skipped by the sink of the stage (see OutputSink::skip).
+    Zeros(u64),
+    /// the end of the data.
+    End,
+}
+
+/// Passes the written data in blocks to a stage. Blocks, if the queue of the stage is full.
+/// The end of the data is passed by finish, a stage, whose sender is dropped without it, fails.
+struct StageSender {
+    sender: SyncSender<Block>,
+    block: Vec<u8>,
+    /// time (in ns), which was spent waiting for the stage.
+    backpressure: Arc<AtomicU64>,
+}
+
+impl StageSender {
+    fn send(&mut self) -> io::Result<()> {
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(PIPELINE_BLOCK_SIZE));
+        self.send_block(Block::Data(block))
+    }
+
+    fn send_block(&mut self, block: Block) -> io::Result<()> {
+        let start = Instant::now();
+        self.sender.send(block).map_err(|_| stage_stopped())?;
+        self.backpressure.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Passes the last block and the end of the data to the stage.
+    fn finish(mut self) -> io::Result<()> {
+        self.send_end()
+    }
+
+    fn send_end(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sender.send(Block::End).map_err(|_| stage_stopped())
+    }
+}
+
+fn stage_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the next pipeline stage has stopped")
+}
+
+impl Write for StageSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(PIPELINE_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..size]);
+        if self.block.len() == PIPELINE_BLOCK_SIZE {
+            self.send()?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+/// The sender of a stage is the sink of the previous stage (e.g. of the compression stage), which is finished
+/// after the end of the compressed stream has been written.
+impl OutputSink for StageSender {
+    fn finish(&mut self) -> io::Result<()> {
+        self.send_end()
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.flush()?;
+        self.send_block(Block::Zeros(len))
+    }
+}
+
+#[derive(Default)]
+struct StageStats {
+    bytes: u64,
+    elapsed: Duration,
+    /// time spent waiting for the previous stage.
+    waiting: Duration,
+    /// time the previous stage was blocked by this stage.
+    backpressure: Duration,
+}
+
+impl StageStats {
+    fn report(&self, name: &str) {
+        let throughput = (self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)) as u64;
+        info!("Pipeline stage {name}: {} in {:.2?} ({}/s), waiting for input {:.2?}, blocking the previous stage {:.2?}.",
+            self.bytes.bytes_as_hrb(), self.elapsed, throughput.bytes_as_hrb(), self.waiting, self.backpressure);
+    }
+}
+
+struct Stage {
+    name: &'static str,
+    handle: JoinHandle<io::Result<StageStats>>,
+}
+
+fn spawn_stage(name: &'static str, writer: Box<dyn OutputSink>) -> anyhow::Result<(StageSender, Stage)> {
+    let (sender, receiver) = sync_channel(PIPELINE_QUEUE_DEPTH);
+    let backpressure = Arc::new(AtomicU64::new(0));
+    let stage_backpressure = Arc::clone(&backpressure);
+    let handle = thread::Builder::new()
+        .name(format!("emd-{name}"))
+        .spawn(move || run_stage(receiver, writer, stage_backpressure))?;
+    let sender = StageSender {
+        sender,
+        block: Vec::with_capacity(PIPELINE_BLOCK_SIZE),
+        backpressure,
+    };
+    Ok((sender, Stage { name, handle }))
+}
+
+fn run_stage(receiver: Receiver<Block>, mut writer: Box<dyn OutputSink>, backpressure: Arc<AtomicU64>) -> io::Result<StageStats> {
+    let started = Instant::now();
+    let mut stats = StageStats::default();
+    loop {
+        let start = Instant::now();
+        let block = receiver.recv();
+        stats.waiting += start.elapsed();
+        match block {
+            Ok(Block::Data(data)) => {
+                writer.write_all(&data)?;
+                stats.bytes += data.len() as u64;
+            },
+            Ok(Block::Zeros(len)) => {
+                writer.skip(len)?;
+                stats.bytes += len;
+            },
+            Ok(Block::End) => break,
+            // the output is not finished, so e.g. a truncated image is not completed as if it was complete.
+            Err(_) => return Err(io::Error::other("the previous stage has stopped before the end of the data")),
+        }
+    }
+    writer.finish()?; // finishes the compression or the encryption (if any) and the sink of the stage.
+    stats.elapsed = started.elapsed();
+    stats.backpressure = Duration::from_nanos(backpressure.load(Ordering::Relaxed));
+    Ok(stats)
+}
+
+/// Waits for the given stages. If stages have failed, the errors of all failed stages are returned (e.g. the
+/// compression stage stops, because the writer stage has failed).
+fn join_stages(stages: Vec<Stage>) -> io::Result<Vec<(&'static str, StageStats)>> {
+    let mut stats = Vec::new();
+    let mut errors = Vec::new();
+    let mut kind = None;
+    for stage in stages {
+        match stage.handle.join() {
+            Ok(Ok(stage_stats)) => stats.push((stage.name, stage_stats)),
+            Ok(Err(e)) => {
+                kind.get_or_insert(e.kind());
+                errors.push(format!("the {} stage failed: {e}", stage.name));
+            },
+            Err(_) => errors.push(format!("the {} stage panicked", stage.name)),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(stats),
+        false => Err(io::Error::new(kind.unwrap_or(io::ErrorKind::Other), errors.join("; "))),
+    }
+}
+
+/// A bounded producer/consumer pipeline: the dump loop (the reader stage) writes into the pipeline, which passes the
+/// data in blocks to the next stages (e.g. compression and writer), each running on its own thread.
+pub(crate) struct Pipeline {
+    sender: Option<StageSender>,
+    stages: Vec<Stage>,
+    bytes: u64,
+    started: Instant,
+}
+
+impl Pipeline {
+    /// Creates the pipeline with the given (last) stage.
+    pub(crate) fn new(name: &'static str, writer: Box<dyn OutputSink>) -> anyhow::Result<Self> {
+        let (sender, stage) = spawn_stage(name, writer)?;
+        Ok(Self {
+            sender: Some(sender),
+            stages: vec![stage],
+            bytes: 0,
+            started: Instant::now(),
+        })
+    }
+
+    /// Adds a stage in front of the existing stages. The given function creates the writer of the stage,
+    /// based on the writer to the next stage.
+    pub(crate) fn prepend<F>(&mut self, name: &'static str, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Box<dyn OutputSink>) -> anyhow::Result<Box<dyn OutputSink>>,
+    {
+        let next = self.sender.take().unwrap();
+        let (sender, stage) = spawn_stage(name, f(Box::new(next))?)?;
+        self.sender = Some(sender);
+        self.stages.insert(0, stage);
+        Ok(())
+    }
+
+    /// Returns the number of bytes, which were written into the pipeline.
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Passes the given operation to the sender of the first stage. If the stage has stopped, the cause is returned.
+    fn send<T>(&mut self, f: impl FnOnce(&mut StageSender) -> io::Result<T>) -> io::Result<T> {
+        let Some(sender) = &mut self.sender else {
+            return Err(pipeline_stopped());
+        };
+        match f(sender) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                self.sender = None;
+                join_stages(std::mem::take(&mut self.stages))?;
+                Err(e)
+            },
+        }
+    }
+}
+
+fn pipeline_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the pipeline has stopped")
+}
+
+impl OutputSink for Pipeline {
+    /// Passes the remaining data through all stages, waits for them and reports the stats of each stage.
+    fn finish(&mut self) -> io::Result<()> {
+        let sent = self.sender.take().ok_or_else(pipeline_stopped)?.finish();
+        let elapsed = self.started.elapsed();
+        // if the send has failed, the cause is returned by the stages.
+        let stats = join_stages(std::mem::take(&mut self.stages))?;
+        sent?;
+        StageStats { bytes: self.bytes, elapsed, ..Default::default() }.report("reader");
+        for (name, stage_stats) in stats {
+            stage_stats.report(name);
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.send(|sender| sender.skip(len))?;
+        self.bytes += len;
+        Ok(())
+    }
+}
+
+impl Write for Pipeline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.send(|sender| sender.write(buf))?;
+        self.bytes += size as u64;
+        Ok(size)
+    }
+
+    /// The data is passed in blocks to the next stage, the last block is passed by finish().
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn test_data() -> Vec<u8> {
+        pattern(0..3 * PIPELINE_BLOCK_SIZE as u64 + 17)
+    }
+
+    #[test]
+    fn data_passes_all_stages() {
+        let sink = SharedSink::default();
+        let mut pipeline = Pipeline::new("writer", Box::new(sink.clone())).unwrap();
+        pipeline.prepend("copy", Ok).unwrap();
+        let data = test_data();
+        pipeline.write_all(&data).unwrap();
+        pipeline.finish().unwrap();
+        assert_eq!(sink.data(), data);
+        assert!(sink.is_finished());
+    }
+
+    #[test]
+    fn zeros_are_skipped_by_the_last_stage() {
+        let sink = SharedSink::default();
+        let mut pipeline = Pipeline::new("writer", Box::new(sink.clone())).unwrap();
+        pipeline.prepend("copy", Ok).unwrap();
+        let data = test_data();
+        pipeline.write_all(&data).unwrap();
+        pipeline.skip(0x12345).unwrap();
+        pipeline.write_all(&data).unwrap();
+        assert_eq!(pipeline.bytes_written(), 2 * data.len() as u64 + 0x12345);
+        pipeline.finish().unwrap();
+
+        let mut expected = data.clone();
+        expected.resize(data.len() + 0x12345, 0);
+        expected.extend_from_slice(&data);
+        assert_eq!(sink.data(), expected);
+        assert_eq!(sink.skipped.load(Ordering::SeqCst), 0x12345);
+    }
+
+    #[test]
+    fn failing_stage_fails_the_pipeline() {
+        let mut pipeline = Pipeline::new("writer", Box::new(FailingSink)).unwrap();
+        pipeline.prepend("copy", Ok).unwrap();
+        let result = pipeline.write_all(&test_data()).and_then(|_| pipeline.finish());
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("the writer stage failed: disk full"), "{error}");
+    }
+
+    #[test]
+    fn failing_finish_fails_the_pipeline() {
+        let sink = SharedSink { fail_finish: true, ..SharedSink::default() };
+        let mut pipeline = Pipeline::new("writer", Box::new(sink)).unwrap();
+        pipeline.write_all(b"memory").unwrap();
+        let error = pipeline.finish().unwrap_err().to_string();
+        assert!(error.contains("the writer stage failed: disk full"), "{error}");
+    }
+
+    #[test]
+    fn stage_without_end_of_data_is_not_finished() {
+        let sink = SharedSink::default();
+        let (mut sender, stage) = spawn_stage("writer", Box::new(sink.clone())).unwrap();
+        sender.write_all(&test_data()).unwrap();
+        drop(sender);
+        assert!(join_stages(vec![stage]).is_err());
+        assert!(!sink.is_finished());
+    }
+}
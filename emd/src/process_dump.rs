@@ -0,0 +1,192 @@
+// - STD
+use std::fs::read_to_string;
+
+// - parent
+use super::*;
+
+// - External
+use procfs::process::{MMapPath, MemoryMap, MemoryPageFlags, PageInfo};
+
+use crate::traits::HumanReadable;
+
+/// Dumps the readable memory mappings of a single process as ELF core file (one PT_LOAD per mapping).
+///
+/// bpf_probe_read_user can only read the address space of the current task (emd itself), so the virtual
+/// addresses of the target are translated to physical pages by /proc/<pid>/pagemap, which are read through
+/// the kernel's direct mapping (page_offset_base). This does not need ptrace, so it works with Yama and lockdown.
+/// Pages which are not present (swapped out or never touched) are written as zeros.
+pub(crate) fn dump_process_memory(
+    args: &Cli,
+    pid: i32,
+    reader: &mut MemoryReader,
+    output: Box<dyn OutputSink>,
+    progress: &mut Progress,
+) -> anyhow::Result<()> {
+    // the default output format (lime) is replaced by the ELF core file.
+    if !matches!(args.output_format, OutputFormat::Lime | OutputFormat::Elf) {
+        anyhow::bail!("Process memory is always dumped as ELF core file and cannot be combined with --output-format.");
+    }
+    if matches!(args.gap_mode, GapMode::Split) {
+        anyhow::bail!("The gap mode split is not supported with --pid (unreadable pages are zero filled).");
+    }
+    if args.resume {
+        anyhow::bail!("--resume is not supported with --pid.");
+    }
+    info!("Calculating page offset base.");
+    let page_offset_base = get_page_offset_base(reader.transport)?;
+
+    let process = Process::new(pid)?;
+    info!("Extracting memory mappings of PID {pid}.");
+    let mappings: Vec<MemoryMap> = process.maps()?
+        .into_iter()
+        .filter(|mapping| mapping.perms.contains(MMPermissions::READ) && !matches!(mapping.pathname, MMapPath::Vsyscall))
+        .collect();
+    let memory_size: u64 = mappings.iter().map(|mapping| mapping.address.1 - mapping.address.0).sum();
+    info!("Total size to dump: {}", memory_size.bytes_as_hrb());
+
+    let mut notes = Vec::new();
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_OSRELEASE, get_os_release()?.as_bytes()));
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_VERSION, env!("CARGO_PKG_VERSION").as_bytes()));
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_PID, &pid.to_le_bytes()));
+    notes.extend(elf_note_as_bytes(EMD_NOTE_NAME, EMD_NOTE_PROC_MAPS, read_to_string(format!("/proc/{pid}/maps"))?.as_bytes()));
+    let segments: Vec<ElfSegment> = mappings.iter().map(|mapping| ElfSegment {
+        vaddr: mapping.address.0,
+        paddr: 0,
+        size: mapping.address.1 - mapping.address.0,
+        flags: segment_flags(mapping),
+    }).collect();
+
+    let mut metadata = match args.metadata {
+        Some(_) => Some(AcquisitionMetadata::new(args, page_offset_base)?),
+        None => None,
+    };
+    let range_hash_algorithms = hash_algorithms_or_default(args);
+    let hashes = ImageHashes::new(args);
+    let mut output_file = prepare_writer(args, &hashes, output)?;
+    output_file.write_all(&elf_prologue(&segments, &notes)?)?;
+
+    progress.start(memory_size);
+    let page_size = procfs::page_size();
+    let zero_page = vec![0u8; page_size as usize];
+    let mut pagemap = process.pagemap()?;
+    let mut interrupted_at = None;
+
+    for mapping in &mappings {
+        let (start, end) = mapping.address;
+        let mut range_record = metadata.as_ref().map(|_| RangeRecord::new(&(start..end), &range_hash_algorithms));
+        if interrupted_at.is_some() {
+            // the segment headers announce all mappings, so the mappings behind the interruption are zero filled.
+            zero_fill(&mut RawFormat, &mut output_file, start..end, range_record.as_mut())?;
+            if let (Some(metadata), Some(range_record)) = (&mut metadata, range_record) {
+                metadata.push_range(range_record);
+            }
+            continue;
+        }
+        info!("Dumping 0x{start:x} - 0x{end:x} ({:?})", mapping.pathname);
+        let page_infos = pagemap.get_range_info((start / page_size) as usize..(end / page_size) as usize)?;
+
+        let mut zero_start = None;
+        let mut index = 0;
+        while index < page_infos.len() {
+            let address = start + index as u64 * page_size;
+            if reader.cancellation.is_cancelled() {
+                interrupted_at = Some(address);
+                if let (Some(zero_start), Some(range_record)) = (zero_start.take(), &mut range_record) {
+                    range_record.zero_filled(zero_start..address);
+                }
+                zero_fill(&mut RawFormat, &mut output_file, address..end, range_record.as_mut())?;
+                break;
+            }
+            let Some(pfn) = page_frame_number(&page_infos[index]) else {
+                zero_start.get_or_insert(address);
+                output_file.write_all(&zero_page)?;
+                if let Some(range_record) = &mut range_record {
+                    range_record.update(&zero_page);
+                }
+                index += 1;
+                continue;
+            };
+            if let Some(zero_start) = zero_start.take() {
+                debug!("Pages 0x{zero_start:x} - 0x{address:x} are not present. Writing zeros for appropriate zone.");
+                if let Some(range_record) = &mut range_record {
+                    range_record.zero_filled(zero_start..address);
+                }
+            }
+
+            // physically contiguous pages are read at once.
+            let mut pages = 1;
+            while index + pages < page_infos.len()
+                && (pages + 1) as u64 * page_size <= MAX_QUEUE_SIZE as u64
+                && page_frame_number(&page_infos[index + pages]) == Some(pfn + pages as u64) {
+                pages += 1;
+            }
+            let dump_size = pages * page_size as usize;
+            reader.transport.read(page_offset_base + pfn * page_size, dump_size, |element| {
+                let element_address = address + element.offset as u64;
+                for (zone, _) in element.zones().into_iter().filter(|(_, readable)| !readable) {
+                    let zone = element_address + zone.start as u64..element_address + zone.end as u64;
+                    warn!("Could not read 0x{:x} - 0x{:x}. Writing zeros for appropriate zone.", zone.start, zone.end);
+                    if let Some(range_record) = &mut range_record {
+                        range_record.zero_filled(zone);
+                    }
+                }
+                output_file.write_all(element.data_or_zeros())?;
+                if let Some(range_record) = &mut range_record {
+                    range_record.update(element.data_or_zeros());
+                }
+                Ok(())
+            })?;
+            index += pages;
+        }
+        if let Some(zero_start) = zero_start {
+            debug!("Pages 0x{zero_start:x} - 0x{end:x} are not present. Writing zeros for appropriate zone.");
+            if let Some(range_record) = &mut range_record {
+                range_record.zero_filled(zero_start..end);
+            }
+        }
+        progress.inc(end - start);
+        if let (Some(metadata), Some(range_record)) = (&mut metadata, range_record) {
+            metadata.push_range(range_record);
+        }
+    }
+    output_file.finish()?; // finishes all stages (and the compression, if any), before the hashes are finalized.
+    if let Some(address) = interrupted_at {
+        warn!("The acquisition has been interrupted at 0x{address:x}. The image is incomplete (the rest of the mappings is zero filled).");
+        if let Some(metadata) = &mut metadata {
+            metadata.interrupted(address);
+        }
+    }
+    let digests = hashes.finalize();
+    if !args.hash.is_empty() {
+        digests.report(args)?;
+    }
+    if let (Some(metadata), Some(path)) = (metadata, &args.metadata) {
+        metadata.finish(&digests, path)?;
+    }
+    Ok(())
+}
+
+/// Returns the page frame number of a present page (the pfn is zero, if it is hidden from the caller).
+fn page_frame_number(page_info: &PageInfo) -> Option<u64> {
+    match page_info {
+        PageInfo::MemoryPage(flags) if flags.contains(MemoryPageFlags::PRESENT) => {
+            let pfn = flags.get_page_frame_number().0;
+            (pfn != 0).then_some(pfn)
+        },
+        _ => None,
+    }
+}
+
+fn segment_flags(mapping: &MemoryMap) -> u32 {
+    let mut flags = 0;
+    if mapping.perms.contains(MMPermissions::READ) {
+        flags |= PF_R;
+    }
+    if mapping.perms.contains(MMPermissions::WRITE) {
+        flags |= PF_W;
+    }
+    if mapping.perms.contains(MMPermissions::EXECUTE) {
+        flags |= PF_X;
+    }
+    flags
+}
@@ -0,0 +1,382 @@
+// - STD
+use std::{
+    fs::{OpenOptions, read_to_string, remove_file, rename},
+    io::BufReader,
+    time::{Duration, Instant},
+};
+
+// - parent
+use super::*;
+
+// - External
+use serde::{Deserialize, Serialize};
+
+/// the checkpoint is written at most once per interval (and after each range).
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The state of an acquisition, which is written next to the output (<outputfile>.checkpoint) and used by --resume.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    output_format: String,
+    ranges: Vec<Range<u64>>,
+    /// bytes of the image, which were written when the checkpoint was written (some of them may not have reached the disk).
+    output_size: u64,
+    zero_filled: Vec<Range<u64>>,
+    start_time: String,
+}
+
+fn checkpoint_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".checkpoint");
+    PathBuf::from(path)
+}
+
+/// Returns true, if the output can be resumed: uncompressed and unencrypted raw or lime images in a single file,
+/// where unreadable zones are zero filled (so the position of each range in the file is known).
+pub(crate) fn is_resumable(args: &Cli) -> bool {
+    args.output.is_some()
+        && args.split_size.is_none()
+        && args.recipients.is_empty()
+        && matches!(args.compression, Compression::None)
+        && matches!(args.output_format, OutputFormat::Raw | OutputFormat::Lime)
+        && matches!(args.gap_mode, GapMode::Zero)
+}
+
+fn header_size(args: &Cli) -> u64 {
+    match args.output_format {
+        OutputFormat::Lime => LimeHeader::default().as_bytes().len() as u64,
+        _ => 0,
+    }
+}
+
+/// Returns the offset of the data of each range in the image (behind the LiME header, if any).
+fn data_offsets(args: &Cli, ranges: &[Range<u64>]) -> Vec<u64> {
+    let mut offset = 0;
+    ranges.iter().map(|range| {
+        let data_offset = offset + header_size(args);
+        offset = data_offset + (range.end - range.start);
+        data_offset
+    }).collect()
+}
+
+/// Writes the checkpoint periodically, while the acquisition is running.
+pub(crate) struct CheckpointWriter {
+    path: PathBuf,
+    checkpoint: Checkpoint,
+    last_write: Instant,
+}
+
+impl CheckpointWriter {
+    pub(crate) fn new(args: &Cli, ranges: &[Range<u64>], resume: Option<&Resume>) -> Self {
+        Self {
+            path: checkpoint_path(args.output.as_ref().unwrap()),
+            checkpoint: Checkpoint {
+                output_format: value_name(&args.output_format),
+                ranges: ranges.to_vec(),
+                output_size: 0,
+                zero_filled: resume.map(|resume| resume.zero_filled.clone()).unwrap_or_default(),
+                start_time: resume.map(|resume| resume.start_time.clone()).unwrap_or_else(now),
+            },
+            last_write: Instant::now(),
+        }
+    }
+
+    pub(crate) fn zero_filled(&mut self, zone: Range<u64>) {
+        match self.checkpoint.zero_filled.last_mut() {
+            Some(last) if last.end == zone.start => last.end = zone.end,
+            _ => self.checkpoint.zero_filled.push(zone),
+        }
+    }
+
+    /// Writes the checkpoint with the given image size, if the interval has elapsed (or if forced).
+    pub(crate) fn update(&mut self, output_size: u64, force: bool) -> anyhow::Result<()> {
+        if !force && self.last_write.elapsed() < CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+        self.checkpoint.output_size = output_size;
+        // the checkpoint is replaced atomically, so there is always a complete one.
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut file = File::create(&temp_path)?;
+        serde_json::to_writer(&mut file, &self.checkpoint)?;
+        // otherwise the renamed checkpoint may be empty after a crash.
+        file.sync_all()?;
+        rename(&temp_path, &self.path)?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Removes the checkpoint after the acquisition has been completed.
+    pub(crate) fn remove(self) -> anyhow::Result<()> {
+        if self.path.exists() {
+            remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The position, where an interrupted acquisition is continued.
+pub(crate) struct Resume {
+    pub(crate) range_index: usize,
+    pub(crate) address: u64,
+    /// true, if the LiME header of the range is already part of the image.
+    pub(crate) header_written: bool,
+    pub(crate) output_size: u64,
+    /// bytes of memory, which are already part of the image.
+    pub(crate) data_size: u64,
+    pub(crate) start_time: String,
+    zero_filled: Vec<Range<u64>>,
+    /// the records of the ranges, which are (partially) part of the image.
+    records: Vec<Option<RangeRecord>>,
+}
+
+impl Resume {
+    /// Loads the checkpoint of the partial output, truncates the output behind the last complete page and
+    /// hashes the existing part of the image (the image hashes and - if given - the hashes of the ranges).
+    pub(crate) fn new(args: &Cli, ranges: &[Range<u64>], hashes: &ImageHashes, range_hash_algorithms: Option<&[HashAlgorithm]>) -> anyhow::Result<Self> {
+        if !is_resumable(args) {
+            anyhow::bail!("--resume is only supported for uncompressed and unencrypted raw or lime output files (without --split-size and with --gap-mode zero).");
+        }
+        let output = args.output.as_ref().unwrap();
+        let path = checkpoint_path(output);
+        let checkpoint: Checkpoint = serde_json::from_str(&read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Could not read the checkpoint {}: {e}", path.display()))?)?;
+        if checkpoint.output_format != value_name(&args.output_format) || checkpoint.ranges != ranges {
+            anyhow::bail!("The checkpoint does not match the output format or the memory ranges of this acquisition.");
+        }
+
+        // the checkpoint may be ahead of the data, which has reached the disk.
+        let file = OpenOptions::new().read(true).write(true).open(output)?;
+        let available = file.metadata()?.len().min(checkpoint.output_size);
+        let data_offsets = data_offsets(args, ranges);
+        let mut resume = Self {
+            range_index: ranges.len(),
+            address: 0,
+            header_written: true,
+            output_size: available,
+            data_size: 0,
+            start_time: checkpoint.start_time,
+            zero_filled: Vec::new(),
+            records: Vec::new(),
+        };
+        for (index, (range, data_offset)) in ranges.iter().zip(&data_offsets).enumerate() {
+            let range_size = range.end - range.start;
+            if available >= data_offset + range_size {
+                resume.data_size += range_size;
+                continue;
+            }
+            resume.range_index = index;
+            if available < *data_offset {
+                // the LiME header of the range is incomplete.
+                resume.address = range.start;
+                resume.header_written = false;
+                resume.output_size = data_offset - header_size(args);
+            } else {
+                let resumed_size = (available - data_offset) / READ_PAGE_SIZE as u64 * READ_PAGE_SIZE as u64;
+                resume.address = range.start + resumed_size;
+                resume.output_size = data_offset + resumed_size;
+                resume.data_size += resumed_size;
+            }
+            break;
+        }
+
+        // zero filled zones behind the resume position will be read again.
+        let range_index = resume.range_index;
+        let address = resume.address;
+        resume.zero_filled = checkpoint.zero_filled.into_iter().filter_map(|zone| {
+            match ranges.iter().position(|range| range.contains(&zone.start)) {
+                Some(index) if index < range_index => Some(zone),
+                Some(index) if index == range_index && zone.start < address => Some(zone.start..zone.end.min(address)),
+                _ => None,
+            }
+        }).collect();
+        file.set_len(resume.output_size)?;
+        info!("Resuming at 0x{:x} ({} bytes of the image are already written).", resume.address, resume.output_size);
+
+        // hashes the existing part of the image.
+        let mut reader = BufReader::new(file).take(resume.output_size);
+        let mut buffer = vec![0u8; MAX_QUEUE_SIZE];
+        let mut position = 0;
+        loop {
+            let size = reader.read(&mut buffer)?;
+            if size == 0 {
+                break;
+            }
+            hashes.image.update(&buffer[..size]);
+            if let Some(algorithms) = range_hash_algorithms {
+                resume.update_records(ranges, &data_offsets, algorithms, position, &buffer[..size]);
+            }
+            position += size as u64;
+        }
+        for (record, range) in resume.records.iter_mut().zip(ranges) {
+            if let Some(record) = record {
+                for zone in resume.zero_filled.iter().filter(|zone| range.contains(&zone.start)) {
+                    record.zero_filled(zone.clone());
+                }
+            }
+        }
+        Ok(resume)
+    }
+
+    /// Updates the records of the ranges with the given data of the image (at the given position).
+    fn update_records(&mut self, ranges: &[Range<u64>], data_offsets: &[u64], algorithms: &[HashAlgorithm], position: u64, data: &[u8]) {
+        let end = position + data.len() as u64;
+        for (index, (range, data_offset)) in ranges.iter().zip(data_offsets).enumerate() {
+            let data_end = data_offset + (range.end - range.start);
+            if *data_offset >= end || data_end <= position {
+                continue;
+            }
+            if self.records.len() <= index {
+                self.records.resize_with(index, || None);
+                self.records.push(Some(RangeRecord::new(range, algorithms)));
+            }
+            let start = (*data_offset).max(position) - position;
+            let stop = data_end.min(end) - position;
+            self.records[index].as_mut().unwrap().update(&data[start as usize..stop as usize]);
+        }
+    }
+
+    /// Returns the record of the range with the given index, if the range is (partially) part of the image.
+    pub(crate) fn take_record(&mut self, index: usize) -> Option<RangeRecord> {
+        self.records.get_mut(index).and_then(Option::take)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const RANGES: [Range<u64>; 3] = [0x1000..0x3000, 0x5000..0x9000, 0x10000..0x11000];
+    /// the size of a LiME header.
+    const HEADER: u64 = 0x20;
+
+    /// Writes the first file_size bytes of an image of RANGES and a checkpoint with the given output size and
+    /// zero filled zones. Returns the arguments to resume the acquisition.
+    fn partial_output(dir: &TestDir, output_format: OutputFormat, file_size: u64, checkpoint_size: u64, zero_filled: &[Range<u64>]) -> Cli {
+        let args = Cli {
+            output: Some(dir.path("image")),
+            output_format,
+            resume: true,
+            hash: vec![HashAlgorithm::Sha256],
+            ..Cli::default()
+        };
+        let image = write_image(&args.output_format, &RANGES);
+        std::fs::write(dir.path("image"), &image[..file_size as usize]).unwrap();
+        let mut checkpoint = CheckpointWriter::new(&args, &RANGES, None);
+        for zone in zero_filled {
+            checkpoint.zero_filled(zone.clone());
+        }
+        checkpoint.update(checkpoint_size, true).unwrap();
+        args
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        let hashes = StreamHashes::new(&[HashAlgorithm::Sha256]);
+        hashes.update(data);
+        hashes.finalize().remove(0).1
+    }
+
+    fn image_digest(hashes: &ImageHashes) -> String {
+        hashes.finalize().image.remove(0).1
+    }
+
+    #[test]
+    fn raw_output_is_resumed_at_the_last_complete_page() {
+        let dir = TestDir::new("resume-raw");
+        // 0x923 bytes of the second page of the second range.
+        let args = partial_output(&dir, OutputFormat::Raw, 0x3923, 0x3923, &[]);
+        let hashes = ImageHashes::new(&args);
+        let resume = Resume::new(&args, &RANGES, &hashes, None).unwrap();
+        assert_eq!(resume.range_index, 1);
+        assert_eq!(resume.address, 0x6000);
+        assert!(resume.header_written);
+        assert_eq!(resume.output_size, 0x3000);
+        assert_eq!(resume.data_size, 0x3000);
+        let output = std::fs::read(dir.path("image")).unwrap();
+        assert_eq!(output.len(), 0x3000);
+        assert_eq!(image_digest(&hashes), sha256(&output));
+    }
+
+    #[test]
+    fn checkpoint_and_file_size_are_limited_by_each_other() {
+        let dir = TestDir::new("resume-sizes");
+        // the checkpoint is ahead of the data on the disk.
+        let args = partial_output(&dir, OutputFormat::Raw, 0x2800, 0x5000, &[]);
+        let resume = Resume::new(&args, &RANGES, &ImageHashes::new(&args), None).unwrap();
+        assert_eq!((resume.range_index, resume.address, resume.output_size), (1, 0x5000, 0x2000));
+        // the data on the disk is ahead of the checkpoint.
+        let args = partial_output(&dir, OutputFormat::Raw, 0x7000, 0x5000, &[]);
+        let resume = Resume::new(&args, &RANGES, &ImageHashes::new(&args), None).unwrap();
+        assert_eq!((resume.range_index, resume.address, resume.output_size), (1, 0x8000, 0x5000));
+        assert_eq!(std::fs::metadata(dir.path("image")).unwrap().len(), 0x5000);
+    }
+
+    #[test]
+    fn lime_output_is_resumed_behind_the_header() {
+        let dir = TestDir::new("resume-lime");
+        let second_data = HEADER + 0x2000 + HEADER;
+        let args = partial_output(&dir, OutputFormat::Lime, second_data + 0x1234, second_data + 0x1234, &[]);
+        let resume = Resume::new(&args, &RANGES, &ImageHashes::new(&args), None).unwrap();
+        assert_eq!(resume.range_index, 1);
+        assert_eq!(resume.address, 0x6000);
+        assert!(resume.header_written);
+        assert_eq!(resume.output_size, second_data + 0x1000);
+        assert_eq!(resume.data_size, 0x3000);
+    }
+
+    #[test]
+    fn incomplete_lime_header_is_rewritten() {
+        let dir = TestDir::new("resume-lime-header");
+        let second_header = HEADER + 0x2000;
+        let args = partial_output(&dir, OutputFormat::Lime, second_header + 0x10, second_header + 0x10, &[]);
+        let resume = Resume::new(&args, &RANGES, &ImageHashes::new(&args), None).unwrap();
+        assert_eq!(resume.range_index, 1);
+        assert_eq!(resume.address, 0x5000);
+        assert!(!resume.header_written);
+        assert_eq!(resume.output_size, second_header);
+        assert_eq!(resume.data_size, 0x2000);
+        assert_eq!(std::fs::metadata(dir.path("image")).unwrap().len(), second_header);
+    }
+
+    #[test]
+    fn complete_output_is_not_resumed_within_a_range() {
+        let dir = TestDir::new("resume-complete");
+        let size = 3 * HEADER + 0x7000;
+        let args = partial_output(&dir, OutputFormat::Lime, size, size, &[]);
+        let resume = Resume::new(&args, &RANGES, &ImageHashes::new(&args), None).unwrap();
+        assert_eq!(resume.range_index, RANGES.len());
+        assert_eq!(resume.output_size, size);
+        assert_eq!(resume.data_size, 0x7000);
+    }
+
+    #[test]
+    fn range_records_are_rehashed() {
+        let dir = TestDir::new("resume-records");
+        let second_data = HEADER + 0x2000 + HEADER;
+        let zero_filled = [0x1800..0x2000, 0x5800..0x6800, 0x7000..0x8000, 0x10000..0x10800];
+        let args = partial_output(&dir, OutputFormat::Lime, second_data + 0x1234, second_data + 0x1234, &zero_filled);
+        let mut resume = Resume::new(&args, &RANGES, &ImageHashes::new(&args), Some(&[HashAlgorithm::Sha256])).unwrap();
+        // the zones behind the resume position are read again.
+        assert_eq!(resume.zero_filled, [0x1800..0x2000, 0x5800..0x6000]);
+
+        let first = resume.take_record(0).unwrap().finalize();
+        assert_eq!(first.bytes, 0x2000);
+        assert_eq!(first.hashes["sha256"], sha256(&pattern(RANGES[0].clone())));
+        assert_eq!(first.zero_filled.iter().map(ZoneMetadata::range).collect::<Vec<_>>(), resume.zero_filled[..1]);
+        let second = resume.take_record(1).unwrap().finalize();
+        assert_eq!(second.bytes, 0x1000);
+        assert_eq!(second.hashes["sha256"], sha256(&pattern(0x5000..0x6000)));
+        assert_eq!(second.zero_filled.iter().map(ZoneMetadata::range).collect::<Vec<_>>(), resume.zero_filled[1..]);
+        assert!(resume.take_record(2).is_none());
+    }
+
+    #[test]
+    fn checkpoint_of_other_ranges_is_rejected() {
+        let dir = TestDir::new("resume-mismatch");
+        let args = partial_output(&dir, OutputFormat::Raw, 0x3000, 0x3000, &[]);
+        assert!(Resume::new(&args, &RANGES[..2], &ImageHashes::new(&args), None).is_err());
+        let args = Cli { output_format: OutputFormat::Lime, ..args };
+        assert!(Resume::new(&args, &RANGES, &ImageHashes::new(&args), None).is_err());
+    }
+}
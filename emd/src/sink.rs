@@ -0,0 +1,171 @@
+// - STD
+use std::{
+    io::{self, Seek, SeekFrom, Stdout},
+    net::TcpStream,
+};
+
+// - parent
+use super::*;
+
+/// The destination of an image, e.g. a file, stdout or a remote collector. Any writer can be used as sink
+/// (boxed as `Box<dyn Write + Send>`), implement this trait to complete the output explicitly.
+pub trait OutputSink: Write + Send {
+    /// Called once after the last byte of the output (after the compression and encryption are finished).
+    /// Errors are reported as errors of the acquisition.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Writes the given number of zeros, whose size is known up front (e.g. a hole of a padded raw image).
+    /// Seekable sinks (see SparseFile) seek over them instead.
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        io::copy(&mut io::repeat(0).take(len), self)?;
+        Ok(())
+    }
+}
+
+impl OutputSink for File {}
+
+impl OutputSink for Stdout {}
+
+impl OutputSink for TcpStream {}
+
+impl OutputSink for Vec<u8> {}
+
+impl OutputSink for Box<dyn Write + Send> {}
+
+impl OutputSink for SparseFile {
+    fn finish(&mut self) -> io::Result<()> {
+        // the file is extended by a trailing hole.
+        if self.hole > 0 {
+            self.file.set_len(self.offset)?;
+        }
+        self.file.flush()
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        // the zeros are not scanned, they are part of the hole.
+        self.hole += len;
+        self.offset += len;
+        Ok(())
+    }
+}
+
+impl OutputSink for Box<dyn OutputSink> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        (**self).skip(len)
+    }
+}
+
+impl<W: OutputSink> OutputSink for HashWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.finish()
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.hash_zeros(len);
+        self.inner.skip(len)
+    }
+}
+
+/// A file, in which blocks of zeros are seeked over instead of written (e.g. the holes of a padded raw image),
+/// so that they don't occupy any disk space on file systems, which support sparse files.
+pub struct SparseFile {
+    file: File,
+    /// the offset of the next byte in the file.
+    offset: u64,
+    /// the size of the skipped zeros in front of the offset.
+    hole: u64,
+}
+
+impl SparseFile {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            offset: 0,
+            hole: 0,
+        }
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.hole > 0 {
+            self.file.seek(SeekFrom::Current(self.hole as i64))?;
+            self.hole = 0;
+        }
+        self.file.write_all(data)
+    }
+}
+
+impl Write for SparseFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // the data is checked in blocks (aligned to the offset in the file), consecutive data blocks are written at once.
+        let mut start = 0;
+        let mut position = 0;
+        while position < buf.len() {
+            let size = (SPARSE_BLOCK_SIZE - self.offset % SPARSE_BLOCK_SIZE).min((buf.len() - position) as u64) as usize;
+            if buf[position..position + size].iter().all(|byte| *byte == 0) {
+                self.write_data(&buf[start..position])?;
+                self.hole += size as u64;
+                start = position + size;
+            }
+            position += size;
+            self.offset += size as u64;
+        }
+        self.write_data(&buf[start..])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_file_skips_holes() {
+        let path = std::env::temp_dir().join(format!("emd-sparse-{}", std::process::id()));
+        let mut sink = SparseFile::new(File::create(&path).unwrap());
+        sink.write_all(&[0xaa; 0x1800]).unwrap();
+        sink.skip(0x100000).unwrap();
+        // zero blocks of the data are seeked over as well.
+        sink.write_all(&[0; SPARSE_BLOCK_SIZE as usize]).unwrap();
+        sink.write_all(&[0xbb; 0x10]).unwrap();
+        sink.skip(0x2000).unwrap();
+        sink.finish().unwrap();
+
+        let content = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut expected = vec![0xaa; 0x1800];
+        expected.resize(0x1800 + 0x100000 + SPARSE_BLOCK_SIZE as usize, 0);
+        expected.extend_from_slice(&[0xbb; 0x10]);
+        expected.resize(expected.len() + 0x2000, 0);
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn skipped_zeros_are_hashed() {
+        let algorithms = [HashAlgorithm::Sha256];
+        let written = StreamHashes::new(&algorithms);
+        let mut writer = HashWriter::new(Vec::new(), written.clone());
+        writer.write_all(&[0xaa; 0x10]).unwrap();
+        writer.write_all(&vec![0; 0x12345]).unwrap();
+
+        let skipped = StreamHashes::new(&algorithms);
+        let mut writer = HashWriter::new(Vec::new(), skipped.clone());
+        writer.write_all(&[0xaa; 0x10]).unwrap();
+        writer.skip(0x12345).unwrap();
+        assert_eq!(writer.inner.len(), 0x10 + 0x12345);
+        let digests = |hashes: StreamHashes| hashes.finalize().into_iter().map(|(_, digest)| digest).collect::<Vec<String>>();
+        assert_eq!(digests(skipped), digests(written));
+    }
+}
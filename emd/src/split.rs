@@ -0,0 +1,228 @@
+// - STD
+use std::collections::BTreeMap;
+
+// - parent
+use super::*;
+
+// - External
+use serde::Serialize;
+
+/// Parses a size with an optional binary suffix (K, M, G, T), e.g. "4G".
+pub(crate) fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        Some('T') => (&value[..value.len() - 1], 1 << 40),
+        _ => (value, 1),
+    };
+    let size = number.parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size: {value}"))?;
+    if size == 0 {
+        return Err("the size must be greater than zero".to_string());
+    }
+    Ok(size)
+}
+
+/// The manifest, which describes the segments of a split output (written to <outputfile>.manifest).
+#[derive(Serialize)]
+struct SplitManifest {
+    split_size: u64,
+    total_size: u64,
+    segments: Vec<SegmentMetadata>,
+}
+
+#[derive(Serialize)]
+struct SegmentMetadata {
+    index: usize,
+    file: String,
+    size: u64,
+    hashes: BTreeMap<String, String>,
+}
+
+struct Segment {
+    file: File,
+    name: String,
+    size: u64,
+    hashes: StreamHashes,
+}
+
+/// Writes the output to segment files (<outputfile>.000, <outputfile>.001, ...) of at most split_size bytes.
+/// The manifest is only written when the writer is finished, so the segments of an aborted acquisition are not
+/// described as a complete output.
+pub(crate) struct SplitWriter {
+    base: PathBuf,
+    split_size: u64,
+    algorithms: Vec<HashAlgorithm>,
+    current: Option<Segment>,
+    segments: Vec<SegmentMetadata>,
+    finished: bool,
+}
+
+impl SplitWriter {
+    pub(crate) fn new(base: &Path, split_size: u64, algorithms: Vec<HashAlgorithm>) -> Self {
+        Self {
+            base: base.to_path_buf(),
+            split_size,
+            algorithms,
+            current: None,
+            segments: Vec::new(),
+            finished: false,
+        }
+    }
+
+    fn segment_path(&self, extension: &str) -> PathBuf {
+        let mut path = self.base.clone().into_os_string();
+        path.push(format!(".{extension}"));
+        PathBuf::from(path)
+    }
+
+    fn close_segment(&mut self) -> std::io::Result<()> {
+        if let Some(mut segment) = self.current.take() {
+            segment.file.flush()?;
+            self.segments.push(SegmentMetadata {
+                index: self.segments.len(),
+                file: segment.name,
+                size: segment.size,
+                hashes: digests_as_map(&segment.hashes.finalize()),
+            });
+        }
+        Ok(())
+    }
+
+    fn open_segment(&mut self) -> std::io::Result<()> {
+        let path = self.segment_path(&format!("{:03}", self.segments.len()));
+        info!("Writing segment {}.", path.display());
+        self.current = Some(Segment {
+            file: File::create(&path)?,
+            name: path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+            size: 0,
+            hashes: StreamHashes::new(&self.algorithms),
+        });
+        Ok(())
+    }
+
+}
+
+impl OutputSink for SplitWriter {
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.close_segment()?;
+        let manifest = SplitManifest {
+            split_size: self.split_size,
+            total_size: self.segments.iter().map(|segment| segment.size).sum(),
+            segments: std::mem::take(&mut self.segments),
+        };
+        let file = File::create(self.segment_path("manifest"))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &manifest)?;
+        writer.flush()
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.as_ref().is_some_and(|segment| segment.size >= self.split_size) {
+            self.close_segment()?;
+        }
+        if self.current.is_none() {
+            self.open_segment()?;
+        }
+        let segment = self.current.as_mut().unwrap();
+        let len = buf.len().min((self.split_size - segment.size) as usize);
+        let written = segment.file.write(&buf[..len])?;
+        segment.hashes.update(&buf[..written]);
+        segment.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some(segment) => segment.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn size_suffixes_are_parsed() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("4K"), Ok(4 << 10));
+        assert_eq!(parse_size("4k"), Ok(4 << 10));
+        assert_eq!(parse_size(" 16M "), Ok(16 << 20));
+        assert_eq!(parse_size("4G"), Ok(4 << 30));
+        assert_eq!(parse_size("2T"), Ok(2 << 40));
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("0G").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("-4K").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("16777216T").is_err());
+        assert_eq!(parse_size("16777215T"), Ok(16777215 << 40));
+    }
+
+    #[test]
+    fn segments_roll_over_at_the_split_size() {
+        let dir = TestDir::new("split-rollover");
+        let base = dir.path("image.lime");
+        let data = pattern(0..0x2800);
+        let mut writer = SplitWriter::new(&base, 0x1000, vec![HashAlgorithm::Sha256]);
+        // unaligned writes, which cross the segment boundaries.
+        for chunk in data.chunks(0x700) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let segments: Vec<Vec<u8>> = ["000", "001", "002"].iter()
+            .map(|extension| std::fs::read(dir.path(&format!("image.lime.{extension}"))).unwrap())
+            .collect();
+        assert_eq!(segments.iter().map(Vec::len).collect::<Vec<_>>(), [0x1000, 0x1000, 0x800]);
+        assert_eq!(segments.concat(), data);
+        assert!(!dir.path("image.lime.003").exists());
+
+        let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.path("image.lime.manifest")).unwrap()).unwrap();
+        assert_eq!(manifest["split_size"], 0x1000);
+        assert_eq!(manifest["total_size"], 0x2800);
+        let entries = manifest["segments"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        for (index, (entry, segment)) in entries.iter().zip(&segments).enumerate() {
+            assert_eq!(entry["index"], index);
+            assert_eq!(entry["file"], format!("image.lime.{index:03}"));
+            assert_eq!(entry["size"], segment.len());
+            let digest: String = Sha256::digest(segment).iter().map(|byte| format!("{byte:02x}")).collect();
+            assert_eq!(entry["hashes"]["sha256"], digest);
+        }
+    }
+
+    #[test]
+    fn dropped_writer_writes_no_manifest() {
+        let dir = TestDir::new("split-dropped");
+        let base = dir.path("image.lime");
+        let mut writer = SplitWriter::new(&base, 0x1000, vec![HashAlgorithm::Sha256]);
+        writer.write_all(&pattern(0..0x1800)).unwrap();
+        drop(writer);
+        assert!(dir.path("image.lime.001").exists());
+        assert!(!dir.path("image.lime.manifest").exists());
+    }
+}
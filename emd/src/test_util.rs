@@ -0,0 +1,176 @@
+// - STD
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+// - parent
+use super::*;
+
+/// Returns the test memory of the given range: each byte is its address modulo 251.
+pub(crate) fn pattern(range: Range<u64>) -> Vec<u8> {
+    range.map(|address| (address % 251) as u8).collect()
+}
+
+/// the page_offset_base of the test images.
+pub(crate) const TEST_PAGE_OFFSET_BASE: u64 = 0xffff888000000000;
+
+/// Writes an image of the given ranges (filled with the pattern) in the given format.
+pub(crate) fn write_image(output_format: &OutputFormat, ranges: &[Range<u64>]) -> Vec<u8> {
+    let mut format = builtin_format(output_format);
+    let mut output = Vec::new();
+    format.begin_image(&mut output, &ImageInfo { ranges, page_offset_base: TEST_PAGE_OFFSET_BASE }).unwrap();
+    for range in ranges {
+        format.begin_range(&mut output, range).unwrap();
+        format.write_data(&mut output, range.start, &pattern(range.clone())).unwrap();
+    }
+    format.finish(&mut output).unwrap();
+    output
+}
+
+/// Collects the records and the data of an image.
+#[derive(Default)]
+pub(crate) struct Records {
+    pub(crate) ranges: Vec<Range<u64>>,
+    pub(crate) data: Vec<u8>,
+}
+
+impl ImageVisitor for Records {
+    fn begin_record(&mut self, range: &Range<u64>) -> anyhow::Result<()> {
+        self.ranges.push(range.clone());
+        Ok(())
+    }
+
+    fn data(&mut self, _address: u64, data: &[u8]) -> anyhow::Result<()> {
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Parses the given (uncompressed) image.
+pub(crate) fn read(image: &[u8], output_format: &OutputFormat) -> (ImageSummary, Records) {
+    let mut records = Records::default();
+    let summary = read_image(image, output_format, &[], &mut records).unwrap();
+    (summary, records)
+}
+
+/// Reads the pattern like MemoryReader, with unreadable pages and cancelled at the given address.
+#[derive(Default)]
+pub(crate) struct TestReader {
+    pub(crate) unreadable: Vec<Range<u64>>,
+    pub(crate) cancel_at: Option<u64>,
+}
+
+impl ZoneReader for TestReader {
+    fn read_zone<F>(&mut self, zone: &Range<u64>, _mapping_offset: u64, mut f: F) -> anyhow::Result<u64>
+    where
+        F: FnMut(u64, Element<'_>) -> anyhow::Result<()>,
+    {
+        for offset in zone.clone().step_by(MAX_QUEUE_SIZE) {
+            if self.cancel_at.is_some_and(|cancel_at| offset >= cancel_at) {
+                return Ok(offset);
+            }
+            let mut data = pattern(offset..zone.end.min(offset + MAX_QUEUE_SIZE as u64));
+            let mut failed_pages = vec![0u32; data.len().div_ceil(BUFFER_SIZE)];
+            for (page, chunk) in data.chunks_mut(READ_PAGE_SIZE).enumerate() {
+                let address = offset + (page * READ_PAGE_SIZE) as u64;
+                if self.unreadable.iter().any(|unreadable| unreadable.contains(&address)) {
+                    chunk.fill(0);
+                    failed_pages[page / PAGES_PER_BUFFER] |= 1 << (page % PAGES_PER_BUFFER);
+                }
+            }
+            for (index, chunk) in data.chunks(BUFFER_SIZE).enumerate() {
+                f(offset, Element { offset: index * BUFFER_SIZE, size: chunk.len(), data: Some(chunk), failed_pages: failed_pages[index] })?;
+            }
+        }
+        Ok(zone.end)
+    }
+}
+
+/// A sink, which shares the written data, the skipped zeros and if it has been finished.
+#[derive(Clone, Default)]
+pub(crate) struct SharedSink {
+    pub(crate) data: Arc<Mutex<Vec<u8>>>,
+    pub(crate) finished: Arc<AtomicBool>,
+    pub(crate) skipped: Arc<AtomicU64>,
+    pub(crate) fail_finish: bool,
+}
+
+impl SharedSink {
+    pub(crate) fn data(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+}
+
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputSink for SharedSink {
+    fn finish(&mut self) -> io::Result<()> {
+        if self.fail_finish {
+            return Err(io::Error::other("disk full"));
+        }
+        self.finished.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.skipped.fetch_add(len, Ordering::SeqCst);
+        let mut data = self.data.lock().unwrap();
+        let size = data.len() + len as usize;
+        data.resize(size, 0);
+        Ok(())
+    }
+}
+
+/// A sink, which fails to write.
+pub(crate) struct FailingSink;
+
+impl Write for FailingSink {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("disk full"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputSink for FailingSink {}
+
+/// A directory for the files of a test, which is removed afterwards.
+pub(crate) struct TestDir(pub(crate) PathBuf);
+
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("emd-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub(crate) fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,258 @@
+// - STD
+use std::{
+    net::TcpStream,
+    sync::Arc,
+};
+
+// - parent
+use super::*;
+
+// - External
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+/// The sending side of a TLS connection. Sends the close_notify alert only when finished, so the collector can
+/// distinguish a complete transfer from a truncated one (e.g. of an aborted acquisition).
+pub(crate) struct TlsSink {
+    stream: StreamOwned<ClientConnection, TcpStream>,
+    closed: bool,
+}
+
+impl OutputSink for TlsSink {
+    fn finish(&mut self) -> std::io::Result<()> {
+        if !self.closed {
+            self.closed = true;
+            self.stream.conn.send_close_notify();
+        }
+        self.stream.flush()
+    }
+}
+
+impl Write for TlsSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Establishes the TLS connection to the collector. The server certificate is verified against the given CA
+/// and/or the pinned SHA-256 fingerprint; a client certificate is presented if set.
+pub(crate) fn connect_tls(args: &Cli, stream: TcpStream) -> anyhow::Result<TlsSink> {
+    let provider = Arc::new(ring::default_provider());
+    let webpki = match &args.tls_ca {
+        Some(path) => Some(WebPkiServerVerifier::builder_with_provider(Arc::new(load_root_store(path)?), provider.clone()).build()?),
+        None => None,
+    };
+    let pin = args.tls_pin.as_deref().map(parse_fingerprint).transpose()?;
+    if webpki.is_none() && pin.is_none() {
+        anyhow::bail!("TLS needs --tls-ca and/or --tls-pin to verify the collector.");
+    }
+    let verifier = ServerVerifier { webpki, pin, provider: provider.clone() };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        _ => builder.with_no_client_auth(),
+    };
+
+    let server_name = match &args.tls_server_name {
+        Some(server_name) => server_name.clone(),
+        None => stream.peer_addr()?.ip().to_string(),
+    };
+    let connection = ClientConnection::new(Arc::new(config), ServerName::try_from(server_name)?)?;
+    let mut stream = StreamOwned::new(connection, stream);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    info!("TLS connection established ({:?}).", stream.conn.negotiated_cipher_suite().map(|suite| suite.suite()));
+    Ok(TlsSink { stream, closed: false })
+}
+
+/// Accepts the TLS connection of an emd instance. If a client CA is set, the client has to authenticate
+/// with a certificate issued by this CA (and matching the pinned fingerprint, if set).
+pub(crate) fn accept_tls(args: &ListenArgs, stream: TcpStream, cert: &Path, key: &Path) -> anyhow::Result<StreamOwned<ServerConnection, TcpStream>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &args.tls_client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_root_store(path)?), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => {
+            warn!("No client CA set (--tls-client-ca); the sending side will not be authenticated.");
+            builder.with_no_client_auth()
+        },
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+
+    let mut stream = StreamOwned::new(ServerConnection::new(Arc::new(config))?, stream);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    if let Some(pin) = &args.tls_client_pin {
+        let pin = parse_fingerprint(pin)?;
+        match stream.conn.peer_certificates().and_then(|certs| certs.first()) {
+            Some(cert) if matches_pin(cert, &pin) => (),
+            _ => anyhow::bail!("The client certificate does not match the pinned fingerprint."),
+        }
+    }
+    info!("TLS connection established ({:?}).", stream.conn.negotiated_cipher_suite().map(|suite| suite.suite()));
+    Ok(stream)
+}
+
+/// Verifies the server certificate by the CA (if set) and by the pinned fingerprint (if set).
+#[derive(Debug)]
+struct ServerVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pin: Option<Vec<u8>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for ServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if let Some(pin) = &self.pin
+            && !matches_pin(end_entity, pin) {
+            return Err(rustls::Error::General("server certificate does not match the pinned fingerprint".to_string()));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}.", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    Ok(PrivateKeyDer::from_pem_file(path)?)
+}
+
+fn load_root_store(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Returns true, if the SHA-256 fingerprint of the given certificate is the pinned one.
+fn matches_pin(cert: &CertificateDer<'_>, pin: &[u8]) -> bool {
+    Sha256::digest(cert.as_ref()).as_slice() == pin
+}
+
+/// Parses a hex encoded SHA-256 fingerprint (colons are allowed, e.g. as printed by openssl).
+fn parse_fingerprint(fingerprint: &str) -> anyhow::Result<Vec<u8>> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    // the hex digits are ASCII, so they can be sliced by bytes.
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid SHA-256 fingerprint: {fingerprint}");
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(anyhow::Error::from))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &[u8] = b"not a real certificate, the pin is only a hash of the DER encoding";
+
+    fn fingerprint(data: &[u8]) -> String {
+        Sha256::digest(data).iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn verify(pin: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = ServerVerifier {
+            webpki: None,
+            pin: Some(parse_fingerprint(pin).unwrap()),
+            provider: Arc::new(ring::default_provider()),
+        };
+        let server_name = ServerName::try_from("collector").unwrap();
+        verifier.verify_server_cert(&CertificateDer::from(CERT), &[], &server_name, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn fingerprint_is_parsed() {
+        let expected: Vec<u8> = (0..32).collect();
+        let hex: String = expected.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(parse_fingerprint(&hex).unwrap(), expected);
+        assert_eq!(parse_fingerprint(&hex.to_uppercase()).unwrap(), expected);
+        let colons = expected.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), expected);
+    }
+
+    #[test]
+    fn invalid_fingerprint_is_rejected() {
+        let hex = "ab".repeat(32);
+        assert!(parse_fingerprint(&hex[..62]).is_err());
+        assert!(parse_fingerprint(&format!("{hex}ab")).is_err());
+        assert!(parse_fingerprint(&hex.replacen('a', "g", 1)).is_err());
+        assert!(parse_fingerprint(&hex.replacen('a', "+", 1)).is_err());
+        // 64 bytes, but not 64 hex digits (the slices would not be on char boundaries).
+        assert!(parse_fingerprint(&format!("a{}a", "é".repeat(31))).is_err());
+        assert!(parse_fingerprint("").is_err());
+    }
+
+    #[test]
+    fn pinned_certificate_is_accepted() {
+        assert!(verify(&fingerprint(CERT)).is_ok());
+        assert!(matches_pin(&CertificateDer::from(CERT), &parse_fingerprint(&fingerprint(CERT)).unwrap()));
+    }
+
+    #[test]
+    fn other_certificate_is_rejected() {
+        assert!(verify(&fingerprint(b"another certificate")).is_err());
+        assert!(!matches_pin(&CertificateDer::from(CERT), &parse_fingerprint(&fingerprint(b"another certificate")).unwrap()));
+    }
+}
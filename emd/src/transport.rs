@@ -0,0 +1,378 @@
+// - parent
+use super::*;
+
+// - External
+use aya::{maps::{Array, RingBuf}, EbpfLoader};
+use procfs::sys::kernel::Version as KernelVersion;
+
+/// Loads the eBPF program with the ring buffer transport, if it is supported by the kernel (5.8+).
+/// Otherwise the eBPF program with the queue transport is loaded.
+/// The slot buffers are sized for the given number of worker threads (see --threads).
+pub(crate) fn load_ebpf(slots: usize) -> anyhow::Result<Ebpf> {
+    let mut loader = EbpfLoader::new();
+    if slots > 0 {
+        loader
+            .set_max_entries(MAP_BUFFER_SLOTS, slots as u32 * QUEUE_SIZE)
+            .set_max_entries(MAP_BUFFER_SLOTS_STATUS, slots as u32);
+    }
+    if KernelVersion::current()? >= KernelVersion::new(5, 8, 0) {
+        match loader.load(emd_ebpf::EBPF_RINGBUF_BINARY) {
+            Ok(ebpf) => return Ok(ebpf),
+            Err(e) => warn!("Could not load the ring buffer eBPF program, falling back to the queue: {e}"),
+        }
+    } else {
+        info!("The kernel does not support ring buffers (5.8+), falling back to the queue.");
+    }
+    Ok(loader.load(emd_ebpf::EBPF_BINARY)?)
+}
+
+/// written instead of unreadable elements.
+pub(crate) static ZERO_BUFFER: [u8; BUFFER_SIZE] = [0u8; BUFFER_SIZE];
+
+/// An element (at most BUFFER_SIZE bytes) of a read request.
+pub(crate) struct Element<'a> {
+    /// offset of the element, relative to the start address of the request.
+    pub(crate) offset: usize,
+    pub(crate) size: usize,
+    pub(crate) data: Option<&'a [u8]>,
+    /// bitmask of the pages (READ_PAGE_SIZE) of the element, which could not be read (they are zeroed).
+    pub(crate) failed_pages: u32,
+}
+
+impl<'a> Element<'a> {
+    fn new(dump_size: usize, index: usize, data: Option<&'a [u8]>, failed_pages: u32) -> Self {
+        Self {
+            offset: index * BUFFER_SIZE,
+            size: (dump_size - index * BUFFER_SIZE).min(BUFFER_SIZE),
+            data,
+            failed_pages,
+        }
+    }
+
+    /// Returns the data or zeros, if the element was not transferred.
+    pub(crate) fn data_or_zeros(&self) -> &[u8] {
+        self.data.unwrap_or(&ZERO_BUFFER[..self.size])
+    }
+
+    pub(crate) fn is_readable(&self) -> bool {
+        self.data.is_some() && self.failed_pages == 0
+    }
+
+    /// Returns the zones (relative to the element) with the information, if they could be read.
+    pub(crate) fn zones(&self) -> Vec<(Range<usize>, bool)> {
+        if self.data.is_none() {
+            return vec![(0..self.size, false)];
+        }
+        let mut zones: Vec<(Range<usize>, bool)> = Vec::new();
+        for page in 0..self.size.div_ceil(READ_PAGE_SIZE) {
+            let readable = self.failed_pages & (1 << page) == 0;
+            let zone = page * READ_PAGE_SIZE..((page + 1) * READ_PAGE_SIZE).min(self.size);
+            match zones.last_mut() {
+                Some((last, last_readable)) if *last_readable == readable => last.end = zone.end,
+                _ => zones.push((zone, readable)),
+            }
+        }
+        zones
+    }
+}
+
+/// The data of a read request, which was completely transferred to userspace.
+pub(crate) struct Chunk {
+    pub(crate) address: u64,
+    data: Vec<u8>,
+    /// the pages (READ_PAGE_SIZE), which could not be read (they are zeroed).
+    failed_pages: Vec<bool>,
+    /// the offset, at which the eBPF program ended the request early (see read_remainder).
+    request_end: Option<usize>,
+}
+
+impl Chunk {
+    pub(crate) fn new(address: u64, dump_size: usize) -> Self {
+        Self::with_buffer(address, dump_size, Vec::new())
+    }
+
+    /// Like new, but reuses the given buffer (e.g. of the previous chunk, see into_buffer).
+    pub(crate) fn with_buffer(address: u64, dump_size: usize, mut data: Vec<u8>) -> Self {
+        data.clear();
+        data.resize(dump_size, 0);
+        Self {
+            address,
+            data,
+            failed_pages: vec![false; dump_size.div_ceil(READ_PAGE_SIZE)],
+            request_end: None,
+        }
+    }
+
+    pub(crate) fn into_buffer(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Notes, if the eBPF program ended the request after an element, which has been read page by page.
+    pub(crate) fn set_request_end(&mut self, status: &ReadStatus) {
+        let bytes_pushed = status.bytes_pushed as usize;
+        if bytes_pushed < self.data.len() && matches!(status.code, 0 | READ_ERROR_PAGES) {
+            self.request_end = Some(bytes_pushed);
+        }
+    }
+
+    /// Reads the remainder of the chunk with the given function, if the eBPF program ended the request early.
+    pub(crate) fn read_remainder<F>(&mut self, mut read: F) -> anyhow::Result<()>
+    where
+        F: FnMut(u64, usize) -> anyhow::Result<Chunk>,
+    {
+        while let Some(offset) = self.request_end.take() {
+            let remainder = read(self.address + offset as u64, self.data.len() - offset)?;
+            self.data[offset..].copy_from_slice(&remainder.data);
+            self.failed_pages[offset / READ_PAGE_SIZE..].copy_from_slice(&remainder.failed_pages);
+            self.request_end = remainder.request_end.map(|end| offset + end);
+        }
+        Ok(())
+    }
+
+    /// Sets the data of the element with the given index and marks its failed pages (as reported by the status).
+    pub(crate) fn set_element(&mut self, index: usize, data: &[u8], status: &ReadStatus) {
+        let offset = index * BUFFER_SIZE;
+        let size = (self.data.len() - offset).min(BUFFER_SIZE);
+        self.data[offset..offset + size].copy_from_slice(&data[..size]);
+        for page in offset / READ_PAGE_SIZE..(offset + size).div_ceil(READ_PAGE_SIZE) {
+            self.failed_pages[page] = status.page_failed(page);
+        }
+    }
+
+    /// Marks all pages of the element with the given index as failed (e.g. if the element was not transferred).
+    pub(crate) fn set_element_failed(&mut self, index: usize) {
+        let offset = index * BUFFER_SIZE;
+        let size = (self.data.len() - offset).min(BUFFER_SIZE);
+        self.data[offset..offset + size].fill(0);
+        for page in offset / READ_PAGE_SIZE..(offset + size).div_ceil(READ_PAGE_SIZE) {
+            self.failed_pages[page] = true;
+        }
+    }
+
+    /// Re-reads the failed pages one by one with the given function, up to retries times per page.
+    pub(crate) fn retry_failed_pages<F>(&mut self, retries: u32, mut read: F) -> anyhow::Result<()>
+    where
+        F: FnMut(u64, usize) -> anyhow::Result<Chunk>,
+    {
+        for page in 0..self.failed_pages.len() {
+            if !self.failed_pages[page] {
+                continue;
+            }
+            let offset = page * READ_PAGE_SIZE;
+            let size = (self.data.len() - offset).min(READ_PAGE_SIZE);
+            let page_address = self.address + offset as u64;
+            for attempt in 1..=retries {
+                let retry = read(page_address, size)?;
+                if !retry.failed_pages[0] {
+                    debug!("Recovered page 0x{page_address:x} (attempt {attempt}).");
+                    self.data[offset..offset + size].copy_from_slice(&retry.data);
+                    self.failed_pages[page] = false;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the elements in order to the given function.
+    pub(crate) fn elements<F>(&self, mut f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(Element<'_>) -> anyhow::Result<()>,
+    {
+        for (index, data) in self.data.chunks(BUFFER_SIZE).enumerate() {
+            let mut failed_pages = 0;
+            for page in 0..PAGES_PER_BUFFER {
+                if self.failed_pages.get(index * PAGES_PER_BUFFER + page) == Some(&true) {
+                    failed_pages |= 1 << page;
+                }
+            }
+            f(Element::new(self.data.len(), index, Some(data), failed_pages))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the status of a read request and returns the number of transferred elements.
+pub(crate) fn check_status(status: &ReadStatus, address: u64, dump_size: usize) -> anyhow::Result<usize> {
+    if status.address != address {
+        anyhow::bail!("The eBPF program did not report a status for the request at 0x{address:x}.");
+    }
+    match status.code {
+        0 => (),
+        READ_ERROR_PAGES => debug!("Some pages of the request at 0x{address:x} could not be read, the first one at 0x{:x}.", status.failed_address),
+        code => warn!("The request at 0x{address:x} failed ({}), {} of {dump_size} bytes were transferred.",
+            read_error_description(code), status.bytes_pushed),
+    }
+    Ok(calc_queue_elements(status.bytes_pushed as usize))
+}
+
+fn read_error_description(code: u32) -> String {
+    match code {
+        READ_ERROR_SOURCE_ADDRESS => "cannot read the source address".to_string(),
+        READ_ERROR_DUMP_SIZE => "cannot read the dump size".to_string(),
+        READ_ERROR_DUMP_SIZE_TOO_LARGE => "the dump size is too large".to_string(),
+        READ_ERROR_PAGES => "some pages could not be read".to_string(),
+        READ_ERROR_PUSH => "the transport is full".to_string(),
+        READ_ERROR_SLOT => "cannot read the slot".to_string(),
+        READ_ERROR_SLOT_OUT_OF_RANGE => "the slot is out of range".to_string(),
+        READ_ERROR_BUFFER => "cannot access the buffer".to_string(),
+        code => format!("unknown error code {code}"),
+    }
+}
+
+/// Returns the index of the element and the data of the given ring buffer record, if the record belongs to the
+/// request at the given address (and is not e.g. a stale record of a previous request).
+fn ringbuf_record(item: &[u8], address: u64, dump_size: usize) -> Option<(usize, &[u8])> {
+    let record_address = u64::from_le_bytes(item.get(0..8)?.try_into().ok()?);
+    let record_size = u64::from_le_bytes(item.get(8..16)?.try_into().ok()?);
+    let offset = record_address.checked_sub(address)?;
+    if offset >= dump_size as u64 || offset % BUFFER_SIZE as u64 != 0 {
+        return None;
+    }
+    let element = Element::new(dump_size, offset as usize / BUFFER_SIZE, None, 0);
+    if record_size != element.size as u64 {
+        return None;
+    }
+    let data = item.get(RINGBUF_RECORD_HEADER_SIZE..RINGBUF_RECORD_HEADER_SIZE + element.size)?;
+    Some((offset as usize / BUFFER_SIZE, data))
+}
+
+/// The buffer between the eBPF program and userspace.
+enum Buffer {
+    /// BPF_MAP_TYPE_QUEUE: one syscall (and one copy) per element.
+    Queue(Queue<MapData, [u8; BUFFER_SIZE]>),
+    /// BPF_MAP_TYPE_RINGBUF (Linux 5.8+): the records are read from the mmap'ed ring buffer (without a syscall per
+    /// element) and copied into the chunk.
+    RingBuf(RingBuf<MapData>),
+}
+
+/// The transport between the eBPF program and userspace: the buffer and the status of the last read request.
+pub(crate) struct Transport {
+    buffer: Buffer,
+    status: Option<Array<MapData, ReadStatus>>,
+    /// how often unreadable pages are re-read.
+    retries: u32,
+    /// the buffer of the last chunk, which is reused by the next one.
+    spare: Vec<u8>,
+}
+
+impl Transport {
+    pub(crate) fn new(ebpf: &mut Ebpf, retries: u32) -> anyhow::Result<Self> {
+        let buffer = if let Some(map) = ebpf.take_map(MAP_BUFFER_RING) {
+            info!("Using ring buffer transport.");
+            Buffer::RingBuf(RingBuf::try_from(map)?)
+        } else {
+            info!("Using queue transport.");
+            Buffer::Queue(Queue::try_from(ebpf.take_map(MAP_BUFFER_QUEUE).unwrap())?)
+        };
+        let status = match ebpf.take_map(MAP_READ_STATUS) {
+            Some(map) => Some(Array::try_from(map)?),
+            None => {
+                info!("The eBPF program does not report the status of read requests, unreadable zones are inferred from the transport.");
+                None
+            },
+        };
+        Ok(Self { buffer, status, retries, spare: Vec::new() })
+    }
+
+    /// Reads dump_size (at most MAX_QUEUE_SIZE) bytes of kernel memory at the given address
+    /// and passes the elements in order to the given function. Unreadable pages are re-read page by page.
+    pub(crate) fn read<F>(&mut self, address: u64, dump_size: usize, f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(Element<'_>) -> anyhow::Result<()>,
+    {
+        let mut chunk = self.read_chunk(address, dump_size)?;
+        chunk.read_remainder(|address, size| self.read_chunk(address, size))?;
+        let retries = self.retries;
+        chunk.retry_failed_pages(retries, |address, size| self.read_chunk(address, size))?;
+        chunk.elements(f)?;
+        self.spare = chunk.into_buffer();
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, address: u64, dump_size: usize) -> anyhow::Result<Chunk> {
+        read_kernel_memory(address, dump_size);
+        let status = match &self.status {
+            Some(status) => status.get(&0, 0)?,
+            // e.g. an eBPF program, which has been built without the status map.
+            None => ReadStatus { bytes_pushed: dump_size as u64, ..ReadStatus::new(address) },
+        };
+        let elements_pushed = check_status(&status, address, dump_size)?;
+        let queue_elements = calc_queue_elements(dump_size);
+        let mut chunk = Chunk::with_buffer(address, dump_size, std::mem::take(&mut self.spare));
+        match &mut self.buffer {
+            Buffer::Queue(buffer_queue) => {
+                for i in 0..queue_elements {
+                    if i >= elements_pushed {
+                        chunk.set_element_failed(i);
+                        continue;
+                    }
+                    match buffer_queue.pop(0) {
+                        Ok(buffer) => chunk.set_element(i, &buffer, &status),
+                        Err(_) => chunk.set_element_failed(i),
+                    }
+                }
+            },
+            Buffer::RingBuf(ring_buf) => {
+                let mut next_index = 0;
+                while let Some(item) = ring_buf.next() {
+                    let record = ringbuf_record(&item, address, dump_size);
+                    let Some((index, data)) = record.filter(|(index, _)| *index >= next_index) else {
+                        warn!("Dropping a ring buffer record, which does not belong to the request at 0x{address:x}.");
+                        continue;
+                    };
+                    // records, which could not be reserved, are missing.
+                    for missing in next_index..index {
+                        chunk.set_element_failed(missing);
+                    }
+                    chunk.set_element(index, data, &status);
+                    next_index = index + 1;
+                }
+                for missing in next_index..queue_elements {
+                    chunk.set_element_failed(missing);
+                }
+            },
+        }
+        chunk.set_request_end(&status);
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(address: u64, size: usize) -> Vec<u8> {
+        let mut item = Vec::new();
+        item.extend_from_slice(&address.to_le_bytes());
+        item.extend_from_slice(&(size as u64).to_le_bytes());
+        item.resize(RINGBUF_RECORD_HEADER_SIZE + BUFFER_SIZE, 0xaa);
+        item
+    }
+
+    #[test]
+    fn ringbuf_records_of_the_request() {
+        let address = 0x10_0000;
+        let dump_size = 2 * BUFFER_SIZE + 0x1000;
+        let first = record(address, BUFFER_SIZE);
+        let (index, data) = ringbuf_record(&first, address, dump_size).unwrap();
+        assert_eq!((index, data.len()), (0, BUFFER_SIZE));
+        let last = record(address + 2 * BUFFER_SIZE as u64, 0x1000);
+        let (index, data) = ringbuf_record(&last, address, dump_size).unwrap();
+        assert_eq!((index, data.len()), (2, 0x1000));
+    }
+
+    #[test]
+    fn foreign_ringbuf_records() {
+        let address = 0x10_0000;
+        let dump_size = 2 * BUFFER_SIZE;
+        // a record of a previous request (below the address), behind the request and in the middle of an element.
+        assert!(ringbuf_record(&record(address - BUFFER_SIZE as u64, BUFFER_SIZE), address, dump_size).is_none());
+        assert!(ringbuf_record(&record(address + dump_size as u64, BUFFER_SIZE), address, dump_size).is_none());
+        assert!(ringbuf_record(&record(address + 0x1000, BUFFER_SIZE), address, dump_size).is_none());
+        // a size, which does not match the element, and a truncated record.
+        assert!(ringbuf_record(&record(address, 0x1000), address, dump_size).is_none());
+        assert!(ringbuf_record(&record(address, BUFFER_SIZE)[..RINGBUF_RECORD_HEADER_SIZE + 0x10], address, dump_size).is_none());
+        assert!(ringbuf_record(&[0u8; 8], address, dump_size).is_none());
+    }
+}
@@ -0,0 +1,536 @@
+// - STD
+use std::{collections::BTreeMap, io};
+
+// - parent
+use super::*;
+
+/// Verifies an existing image (see emd verify): parses the image, compares the covered ranges with the metadata
+/// and/or an iomem snapshot and recomputes the hashes. Fails, if there is any discrepancy.
+pub(crate) fn verify(args: &Cli, verify_args: &VerifyArgs) -> anyhow::Result<()> {
+    let mut discrepancies = Discrepancies::default();
+    let metadata = verify_args.sidecar.as_deref().map(ImageMetadata::from_file).transpose()?;
+    let iomem_ranges = match &verify_args.iomem {
+        Some(path) => Some(iomem_snapshot_ranges(path, &verify_args.iomem_types)?),
+        None => None,
+    };
+    let expected = expected_ranges(metadata.as_ref(), iomem_ranges.as_deref(), &mut discrepancies);
+
+    let output_algorithms = algorithms(metadata.as_ref().map(|metadata| &metadata.output_hashes), &args.hash);
+    let image_algorithms = algorithms(metadata.as_ref().map(|metadata| &metadata.image_hashes), &args.hash);
+    let mut input = ImageInput::open(&verify_args.input, &output_algorithms, &image_algorithms)?;
+    let compression = input.compression.clone();
+    let mut image = input.image()?;
+    let format = image_format(detect_format(&mut image)?, metadata.as_ref());
+    info!("Verifying {} ({}, compression: {}).", verify_args.input.display(), value_name(&format), value_name(&compression));
+
+    if let Some(metadata) = &metadata {
+        check_metadata(metadata, &format, &compression, &mut discrepancies);
+    }
+
+    let range_algorithms = algorithms(metadata.as_ref().and_then(|metadata| metadata.ranges.first().map(|range| &range.hashes)), &[]);
+    // the omitted zones (gap mode split) of a padded raw image are holes.
+    let raw_ranges: Vec<Range<u64>> = expected.iter()
+        .flat_map(|expected| difference(std::slice::from_ref(&expected.range), &expected.skipped))
+        .collect();
+    if matches!(format, OutputFormat::Raw) && raw_ranges.is_empty() {
+        warn!("The ranges of a raw image are unknown without --sidecar or --iomem, only the hashes are calculated.");
+    }
+    let mut verifier = RangeVerifier::new(expected, range_algorithms, matches!(format, OutputFormat::Avml));
+    let summary = read_image(&mut image, &format, &raw_ranges, &mut verifier)?;
+    // the rest of the image (e.g. behind a malformed record) is hashed as well.
+    if let Err(e) = io::copy(&mut image, &mut io::sink()) {
+        debug!("Could not read the rest of the image: {e}");
+    }
+    drop(image);
+    let digests = input.finish();
+
+    info!("Parsed {} bytes with {} records.", summary.size, verifier.records.len());
+    if !summary.end.is_complete() {
+        discrepancies.push(format!("The image is incomplete: {}.", summary.end));
+    }
+    verifier.finish(summary.end.is_complete(), &mut discrepancies);
+
+    if let Some(metadata) = &metadata {
+        report_unreadable_zones(metadata);
+    }
+    compare_digests("image", &digests.image, metadata.as_ref().map(|metadata| &metadata.image_hashes), &mut discrepancies);
+    if !matches!(compression, Compression::None) {
+        match &metadata {
+            Some(metadata) if !metadata.recipients.is_empty() => info!("The output hashes (of the encrypted image) cannot be verified after the decryption."),
+            _ => compare_digests("output", &digests.output, metadata.as_ref().map(|metadata| &metadata.output_hashes), &mut discrepancies),
+        }
+    }
+
+    if discrepancies.0 > 0 {
+        anyhow::bail!("The verification of {} failed with {} discrepancies.", verify_args.input.display(), discrepancies.0);
+    }
+    info!("The image {} has been verified successfully.", verify_args.input.display());
+    Ok(())
+}
+
+/// Counts (and logs) the discrepancies.
+#[derive(Default)]
+pub(crate) struct Discrepancies(pub(crate) usize);
+
+impl Discrepancies {
+    pub(crate) fn push(&mut self, message: String) {
+        error!("{message}");
+        self.0 += 1;
+    }
+}
+
+/// A physical range, which is expected in the image.
+struct ExpectedRange {
+    range: Range<u64>,
+    /// the unreadable zones, which have been omitted (gap mode split).
+    skipped: Vec<Range<u64>>,
+    /// the hashes and the size of the range data (metadata only).
+    hashes: BTreeMap<String, String>,
+    bytes: Option<u64>,
+}
+
+/// Returns the ranges of the metadata or - without metadata - of the iomem snapshot.
+/// The ranges of the metadata have to be part of the iomem snapshot.
+fn expected_ranges(metadata: Option<&ImageMetadata>, iomem_ranges: Option<&[Range<u64>]>, discrepancies: &mut Discrepancies) -> Vec<ExpectedRange> {
+    let Some(metadata) = metadata else {
+        return iomem_ranges.unwrap_or_default().iter().map(|range| ExpectedRange {
+            range: range.clone(),
+            skipped: Vec::new(),
+            hashes: BTreeMap::new(),
+            bytes: None,
+        }).collect();
+    };
+    let expected: Vec<ExpectedRange> = metadata.ranges.iter().map(|range| ExpectedRange {
+        range: range.start..range.end,
+        skipped: range.skipped.iter().map(ZoneMetadata::range).collect(),
+        hashes: range.hashes.clone(),
+        bytes: Some(range.bytes),
+    }).collect();
+    if let Some(iomem_ranges) = iomem_ranges {
+        let ranges: Vec<Range<u64>> = expected.iter().map(|expected| expected.range.clone()).collect();
+        for zone in difference(&ranges, iomem_ranges) {
+            discrepancies.push(format!("The range 0x{:x} - 0x{:x} of the metadata is not part of the iomem snapshot.", zone.start, zone.end - 1));
+        }
+    }
+    expected
+}
+
+fn iomem_snapshot_ranges(path: &Path, iomem_types: &[String]) -> anyhow::Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    for iomem_type in iomem_types {
+        ranges.extend(extract_iomem_ranges_from_file(path, iomem_type)?);
+    }
+    if ranges.is_empty() {
+        anyhow::bail!("There is no iomem region of type {} in {}.", iomem_types.join(", "), path.display());
+    }
+    Ok(merge_ranges(ranges))
+}
+
+fn check_metadata(metadata: &ImageMetadata, format: &OutputFormat, compression: &Compression, discrepancies: &mut Discrepancies) {
+    if metadata.output_format != value_name(format) {
+        discrepancies.push(format!("The image is a {} image, but the metadata describes a {} image.", value_name(format), metadata.output_format));
+    }
+    if metadata.compression.codec != value_name(compression) {
+        discrepancies.push(format!("The image is compressed with {}, but the metadata records {}.", value_name(compression), metadata.compression.codec));
+    }
+    if metadata.status == AcquisitionStatus::Interrupted {
+        let address = metadata.interrupted_at.as_deref().unwrap_or("unknown address");
+        discrepancies.push(format!("The acquisition has been interrupted at {address}, the image is incomplete."));
+    }
+}
+
+/// Returns the algorithms of the given digests (e.g. of the metadata) and the additionally chosen algorithms.
+pub(crate) fn algorithms(digests: Option<&BTreeMap<String, String>>, additional: &[HashAlgorithm]) -> Vec<HashAlgorithm> {
+    let mut algorithms: Vec<HashAlgorithm> = Vec::new();
+    for name in digests.into_iter().flat_map(|digests| digests.keys()) {
+        match HashAlgorithm::from_str(name, true) {
+            Ok(algorithm) => algorithms.push(algorithm),
+            Err(_) => warn!("The hash algorithm {name} is not supported."),
+        }
+    }
+    for algorithm in additional {
+        if !algorithms.iter().any(|known| known.name() == algorithm.name()) {
+            algorithms.push(*algorithm);
+        }
+    }
+    algorithms
+}
+
+pub(crate) fn compare_digests(
+    stream: &str,
+    digests: &[(HashAlgorithm, String)],
+    expected: Option<&BTreeMap<String, String>>,
+    discrepancies: &mut Discrepancies) {
+
+    for (algorithm, digest) in digests {
+        match expected.and_then(|expected| expected.get(&value_name(algorithm))) {
+            Some(expected) if expected.eq_ignore_ascii_case(digest) => info!("{} ({stream}) = {digest} (ok)", algorithm.name()),
+            Some(expected) => discrepancies.push(format!("{} ({stream}) = {digest}, but the metadata records {expected}.", algorithm.name())),
+            None => info!("{} ({stream}) = {digest}", algorithm.name()),
+        }
+    }
+}
+
+fn report_unreadable_zones(metadata: &ImageMetadata) {
+    let zero_filled: Vec<&ZoneMetadata> = metadata.ranges.iter().flat_map(|range| &range.zero_filled).collect();
+    for zone in &zero_filled {
+        warn!("Zero filled (unreadable): 0x{:x} - 0x{:x}", zone.start, zone.end - 1);
+    }
+    let skipped: Vec<&ZoneMetadata> = metadata.ranges.iter().flat_map(|range| &range.skipped).collect();
+    for zone in &skipped {
+        warn!("Skipped (unreadable): 0x{:x} - 0x{:x}", zone.start, zone.end - 1);
+    }
+    let size = |zones: &[&ZoneMetadata]| zones.iter().map(|zone| zone.end - zone.start).sum::<u64>();
+    info!("{} zero filled zones ({} bytes), {} skipped zones ({} bytes).", zero_filled.len(), size(&zero_filled), skipped.len(), size(&skipped));
+}
+
+/// Returns the parts of the ranges, which are not part of the excluded ranges.
+pub(crate) fn difference(ranges: &[Range<u64>], excluded: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut remaining = ranges.to_vec();
+    for excluded in excluded {
+        remaining = remaining.into_iter().flat_map(|range| subtract(range, excluded)).collect();
+    }
+    remaining
+}
+
+/// Collects the records of the image and hashes the data of the expected ranges (like RangeRecord during the acquisition).
+struct RangeVerifier {
+    expected: Vec<ExpectedRange>,
+    algorithms: Vec<HashAlgorithm>,
+    /// holes within a range are zero blocks (avml), which are hashed as zeros.
+    zero_holes: bool,
+    /// the records of the image, in the order of the image.
+    records: Vec<Range<u64>>,
+    records_end: u64,
+    /// the records, which overlap or precede a previous record.
+    unordered: Vec<Range<u64>>,
+    /// the expected range, which is hashed currently, and the next address to hash.
+    current: usize,
+    cursor: u64,
+    hashes: StreamHashes,
+    hashed: u64,
+    /// the digests of the completely hashed ranges.
+    digests: Vec<Option<RangeDigests>>,
+}
+
+struct RangeDigests {
+    digests: Vec<(HashAlgorithm, String)>,
+    /// the number of hashed bytes.
+    hashed: u64,
+}
+
+impl RangeVerifier {
+    fn new(expected: Vec<ExpectedRange>, algorithms: Vec<HashAlgorithm>, zero_holes: bool) -> Self {
+        Self {
+            cursor: expected.first().map_or(0, |expected| expected.range.start),
+            digests: expected.iter().map(|_| None).collect(),
+            hashes: StreamHashes::new(&algorithms),
+            expected,
+            algorithms,
+            zero_holes,
+            records: Vec::new(),
+            records_end: 0,
+            unordered: Vec::new(),
+            current: 0,
+            hashed: 0,
+        }
+    }
+
+    /// Hashes the given zone of the current range (without the skipped zones). Zones without data are hashed as zeros.
+    fn hash_zone(&mut self, zone: Range<u64>, data: Option<&[u8]>) {
+        if self.algorithms.is_empty() || zone.is_empty() {
+            return;
+        }
+        let zeros = [0u8; READ_PAGE_SIZE];
+        for part in difference(std::slice::from_ref(&zone), &self.expected[self.current].skipped) {
+            match data {
+                Some(data) => self.hashes.update(&data[(part.start - zone.start) as usize..(part.end - zone.start) as usize]),
+                None => {
+                    let mut address = part.start;
+                    while address < part.end {
+                        let size = (part.end - address).min(zeros.len() as u64);
+                        self.hashes.update(&zeros[..size as usize]);
+                        address += size;
+                    }
+                },
+            }
+            self.hashed += part.end - part.start;
+        }
+    }
+
+    /// Finishes the current range and continues with the next one.
+    fn finish_range(&mut self) {
+        let end = self.expected[self.current].range.end;
+        if self.zero_holes && self.cursor < end {
+            self.hash_zone(self.cursor..end, None);
+        }
+        let hashes = std::mem::replace(&mut self.hashes, StreamHashes::new(&self.algorithms));
+        self.digests[self.current] = Some(RangeDigests { digests: hashes.finalize(), hashed: self.hashed });
+        self.hashed = 0;
+        self.current += 1;
+        self.cursor = self.expected.get(self.current).map_or(0, |expected| expected.range.start);
+    }
+
+    /// Compares the records and the hashes with the expected ranges. The ranges behind the end of an incomplete
+    /// image are not hashed.
+    fn finish(&mut self, complete: bool, discrepancies: &mut Discrepancies) {
+        for record in &self.unordered {
+            discrepancies.push(format!("The record 0x{:x} - 0x{:x} overlaps or precedes a previous record.", record.start, record.end - 1));
+        }
+        if self.expected.is_empty() {
+            return;
+        }
+        while complete && self.current < self.expected.len() {
+            self.finish_range();
+        }
+
+        let expected_data: Vec<Range<u64>> = self.expected.iter()
+            .flat_map(|expected| difference(std::slice::from_ref(&expected.range), &expected.skipped))
+            .collect();
+        let missing = difference(&expected_data, &self.records);
+        if self.zero_holes {
+            // avml omits ranges, which only contain zeros.
+            let size: u64 = missing.iter().map(|zone| zone.end - zone.start).sum();
+            info!("{size} bytes are omitted as zero blocks.");
+        } else {
+            for zone in missing {
+                discrepancies.push(format!("0x{:x} - 0x{:x} is missing in the image.", zone.start, zone.end - 1));
+            }
+        }
+        for zone in difference(&self.records, &expected_data) {
+            discrepancies.push(format!("0x{:x} - 0x{:x} is not part of the expected ranges.", zone.start, zone.end - 1));
+        }
+
+        for (expected, digests) in self.expected.iter().zip(&self.digests) {
+            let range = format!("0x{:x} - 0x{:x}", expected.range.start, expected.range.end - 1);
+            let Some(RangeDigests { digests, hashed }) = digests.as_ref().filter(|_| !expected.hashes.is_empty()) else {
+                continue;
+            };
+            if let Some(bytes) = expected.bytes.filter(|bytes| bytes != hashed) {
+                discrepancies.push(format!("The range {range} contains {hashed} bytes, but the metadata records {bytes} bytes."));
+            }
+            let mismatches: Vec<&str> = digests.iter()
+                .filter(|(algorithm, digest)| expected.hashes.get(&value_name(algorithm)).is_some_and(|expected| !expected.eq_ignore_ascii_case(digest)))
+                .map(|(algorithm, _)| algorithm.name())
+                .collect();
+            match mismatches.is_empty() {
+                true => info!("Range {range}: ok"),
+                false => discrepancies.push(format!("The {} hashes of the range {range} do not match the metadata.", mismatches.join(", "))),
+            }
+        }
+    }
+}
+
+impl ImageVisitor for RangeVerifier {
+    fn begin_record(&mut self, range: &Range<u64>) -> anyhow::Result<()> {
+        if range.start < self.records_end {
+            self.unordered.push(range.clone());
+        }
+        self.records_end = self.records_end.max(range.end);
+        self.records.push(range.clone());
+        Ok(())
+    }
+
+    fn data(&mut self, mut address: u64, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            // the ranges, which end before the data, are complete.
+            while self.expected.get(self.current).is_some_and(|expected| expected.range.end <= address) {
+                self.finish_range();
+            }
+            let Some(expected) = self.expected.get(self.current) else {
+                break;
+            };
+            // data outside of the expected ranges (or data, which has already been hashed) is reported by finish.
+            let start = expected.range.start.max(self.cursor);
+            if address < start {
+                let size = (start - address).min(data.len() as u64);
+                address += size;
+                data = &data[size as usize..];
+                continue;
+            }
+            let size = (expected.range.end - address).min(data.len() as u64);
+            self.hash_zone(self.cursor..address, None);
+            self.hash_zone(address..address + size, Some(&data[..size as usize]));
+            self.cursor = address + size;
+            address += size;
+            data = &data[size as usize..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const RANGES: [Range<u64>; 2] = [0..2 * MAX_QUEUE_SIZE as u64, 3 * MAX_QUEUE_SIZE as u64..4 * MAX_QUEUE_SIZE as u64];
+
+    /// Dumps RANGES with dump_mem into <dir>/image and writes the metadata to <dir>/image.json.
+    fn dump(dir: &TestDir, args: Cli, mut reader: TestReader) {
+        let args = Cli { metadata: Some(dir.path("image.json")), ..args };
+        let sink = SharedSink::default();
+        dump_mem(&args, &mut reader, RANGES.to_vec(), TEST_PAGE_OFFSET_BASE, Box::new(sink.clone()),
+            builtin_format(&args.output_format), &mut Progress::new(None)).unwrap();
+        std::fs::write(dir.path("image"), sink.data()).unwrap();
+    }
+
+    fn verify_dump(dir: &TestDir) -> anyhow::Result<()> {
+        let verify_args = VerifyArgs {
+            sidecar: Some(dir.path("image.json")),
+            iomem: None,
+            iomem_types: Vec::new(),
+            input: dir.path("image"),
+        };
+        verify(&Cli::default(), &verify_args)
+    }
+
+    fn assert_discrepancies(result: anyhow::Result<()>, count: usize) {
+        let error = result.unwrap_err().to_string();
+        assert!(error.ends_with(&format!("failed with {count} discrepancies.")), "{error}");
+    }
+
+    fn lime_args() -> Cli {
+        Cli { output_format: OutputFormat::Lime, ..Cli::default() }
+    }
+
+    /// Changes the metadata of the dump.
+    fn edit_metadata(dir: &TestDir, f: impl FnOnce(&mut serde_json::Value)) {
+        let path = dir.path("image.json");
+        let mut metadata: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        f(&mut metadata);
+        std::fs::write(&path, serde_json::to_vec(&metadata).unwrap()).unwrap();
+    }
+
+    /// Returns the expected range of the given data (with the hashes and the size of the data, if hashed).
+    fn expected(range: Range<u64>, data: Option<&[u8]>) -> ExpectedRange {
+        let (hashes, bytes) = match data {
+            Some(data) => {
+                let mut record = RangeRecord::new(&range, &[HashAlgorithm::Sha256]);
+                record.update(data);
+                let metadata = record.finalize();
+                (metadata.hashes, Some(metadata.bytes))
+            },
+            None => (BTreeMap::new(), None),
+        };
+        ExpectedRange { range, skipped: Vec::new(), hashes, bytes }
+    }
+
+    /// Passes the given records (filled with the pattern) to the verifier and returns the number of discrepancies.
+    fn verify_records(mut verifier: RangeVerifier, records: &[Range<u64>]) -> usize {
+        for record in records {
+            verifier.begin_record(record).unwrap();
+            verifier.data(record.start, &pattern(record.clone())).unwrap();
+        }
+        let mut discrepancies = Discrepancies::default();
+        verifier.finish(true, &mut discrepancies);
+        discrepancies.0
+    }
+
+    #[test]
+    fn complete_image_is_verified() {
+        let dir = TestDir::new("verify-complete");
+        dump(&dir, lime_args(), TestReader::default());
+        verify_dump(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_image_is_reported() {
+        let dir = TestDir::new("verify-interrupted");
+        dump(&dir, lime_args(), TestReader { cancel_at: Some(MAX_QUEUE_SIZE as u64), ..TestReader::default() });
+        // the zero filled data matches the range hashes, only the status is reported.
+        assert_discrepancies(verify_dump(&dir), 1);
+        let metadata = ImageMetadata::from_file(&dir.path("image.json")).unwrap();
+        let mut discrepancies = Discrepancies::default();
+        check_metadata(&metadata, &OutputFormat::Lime, &Compression::None, &mut discrepancies);
+        assert_eq!(discrepancies.0, 1);
+    }
+
+    #[test]
+    fn range_hash_mismatch_is_reported() {
+        let dir = TestDir::new("verify-hash-mismatch");
+        dump(&dir, lime_args(), TestReader::default());
+        let mut image = std::fs::read(dir.path("image")).unwrap();
+        // a byte of the data of the second record (behind its LiME header).
+        let offset = image.len() - 0x100;
+        image[offset] ^= 0xff;
+        std::fs::write(dir.path("image"), image).unwrap();
+        assert_discrepancies(verify_dump(&dir), 1);
+    }
+
+    #[test]
+    fn size_mismatch_is_reported() {
+        let dir = TestDir::new("verify-size-mismatch");
+        dump(&dir, lime_args(), TestReader::default());
+        edit_metadata(&dir, |metadata| metadata["ranges"][1]["bytes"] = (MAX_QUEUE_SIZE - 1).into());
+        assert_discrepancies(verify_dump(&dir), 1);
+    }
+
+    #[test]
+    fn skipped_zones_are_not_missing() {
+        let dir = TestDir::new("verify-skipped");
+        let reader = TestReader { unreadable: vec![0x5000..0x7000, 0x10000..0x11000], ..TestReader::default() };
+        dump(&dir, Cli { gap_mode: GapMode::Split, ..lime_args() }, reader);
+        let metadata = ImageMetadata::from_file(&dir.path("image.json")).unwrap();
+        assert_eq!(metadata.ranges[0].skipped.len(), 2);
+        verify_dump(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_range_is_reported() {
+        let verifier = RangeVerifier::new(RANGES.iter().map(|range| expected(range.clone(), None)).collect(), Vec::new(), false);
+        assert_eq!(verify_records(verifier, &RANGES[..1]), 1);
+    }
+
+    #[test]
+    fn extra_data_is_reported() {
+        let verifier = RangeVerifier::new(vec![expected(RANGES[0].clone(), None)], Vec::new(), false);
+        assert_eq!(verify_records(verifier, &RANGES), 1);
+        let verifier = RangeVerifier::new(vec![expected(0x1000..0x2000, None)], Vec::new(), false);
+        assert_eq!(verify_records(verifier, std::slice::from_ref(&(0x1000..0x3000))), 1);
+    }
+
+    #[test]
+    fn avml_zero_holes_are_hashed_as_zeros() {
+        let range = 0..0x3000;
+        let mut data = pattern(range.clone());
+        data[0x1000..0x2000].fill(0);
+        let records = [0..0x1000, 0x2000..0x3000];
+        let verifier = RangeVerifier::new(vec![expected(range.clone(), Some(&data))], vec![HashAlgorithm::Sha256], true);
+        assert_eq!(verify_records(verifier, &records), 0);
+        // without zero holes, the omitted zeros are missing.
+        let verifier = RangeVerifier::new(vec![expected(range.clone(), Some(&data))], vec![HashAlgorithm::Sha256], false);
+        assert_eq!(verify_records(verifier, &records), 1);
+        // a zero hole at the end of the range.
+        let verifier = RangeVerifier::new(vec![expected(0..0x2000, Some(&data[..0x2000]))], vec![HashAlgorithm::Sha256], true);
+        assert_eq!(verify_records(verifier, &records[..1]), 0);
+    }
+
+    #[test]
+    fn metadata_ranges_outside_of_iomem_are_reported() {
+        let dir = TestDir::new("verify-iomem");
+        dump(&dir, lime_args(), TestReader::default());
+        let metadata = ImageMetadata::from_file(&dir.path("image.json")).unwrap();
+        let mut discrepancies = Discrepancies::default();
+        let expected = expected_ranges(Some(&metadata), Some(&RANGES), &mut discrepancies);
+        assert_eq!(expected.iter().map(|expected| expected.range.clone()).collect::<Vec<_>>(), RANGES);
+        assert_eq!(discrepancies.0, 0);
+        expected_ranges(Some(&metadata), Some(&RANGES[..1]), &mut discrepancies);
+        assert_eq!(discrepancies.0, 1);
+        // without metadata, the iomem ranges are expected.
+        assert_eq!(expected_ranges(None, Some(&RANGES[1..]), &mut discrepancies).len(), 1);
+        assert_eq!(discrepancies.0, 1);
+    }
+
+    #[test]
+    fn format_and_compression_are_checked() {
+        let dir = TestDir::new("verify-check-metadata");
+        dump(&dir, lime_args(), TestReader::default());
+        let metadata = ImageMetadata::from_file(&dir.path("image.json")).unwrap();
+        let mut discrepancies = Discrepancies::default();
+        check_metadata(&metadata, &OutputFormat::Lime, &Compression::None, &mut discrepancies);
+        assert_eq!(discrepancies.0, 0);
+        check_metadata(&metadata, &OutputFormat::Elf, &Compression::Zstd, &mut discrepancies);
+        assert_eq!(discrepancies.0, 2);
+    }
+}